@@ -3,13 +3,15 @@ use std::error;
 use std::fmt::{Display, Formatter};
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::collections::HashSet;
+use std::fmt::Write;
 use crate::{op, Pgm};
+use crate::op::{Flow, Oparg};
+use crate::debug::{DebugInfo, Symbol};
 
 // Regular expressions used by the assembler.
 // lazy static takes care that they are compiled only once and then reused.
 lazy_static! {
-    static ref ANY_WHITESPACES: Regex = regex::Regex::new(r"\s+").unwrap();
-    static ref OP_LINE_RE: Regex = regex::Regex::new(r"^(\S+)(?: (.+))?$").unwrap();
     static ref VALID_LABEL: Regex = regex::Regex::new(r"^[A-Za-z][0-9A-Za-z_]{0,31}$").unwrap();
 }
 
@@ -29,11 +31,39 @@ pub enum AsmError {
     DuplicateVariable(String),
     UnknownVariable(String),
     TooManyVariables,
+    UnknownSyscall(String),
+    TooManyConstants,
+    UnknownDirective(String),
+    InvalidData,
+    DuplicateData(String),
+    UnknownData(String),
+    TooMuchData,
 }
 
 impl Display for AsmError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            AsmError::InvalidLine => write!(f, "invalid line"),
+            AsmError::UnknownInstruction(name) => write!(f, "unknown instruction `{}`", name),
+            AsmError::UnexpectedArgument => write!(f, "instruction takes no argument"),
+            AsmError::MissingArgument => write!(f, "instruction needs an argument"),
+            AsmError::InvalidArgument => write!(f, "invalid argument"),
+            AsmError::InvalidLabel(name) => write!(f, "invalid name `{}`", name),
+            AsmError::DuplicateLabel(name) => write!(f, "label `{}` is defined more than once", name),
+            AsmError::UnknownLabel(name) => write!(f, "unknown label `{}`", name),
+            AsmError::JumpTooLong => write!(f, "jump destination is too far away"),
+            AsmError::InvalidVariable(name) => write!(f, "invalid variable name `{}`", name),
+            AsmError::DuplicateVariable(name) => write!(f, "local variable `{}` is declared more than once", name),
+            AsmError::UnknownVariable(name) => write!(f, "unknown local variable `{}`", name),
+            AsmError::TooManyVariables => write!(f, "too many variables, there can only be 256"),
+            AsmError::UnknownSyscall(name) => write!(f, "unknown native function `{}`", name),
+            AsmError::TooManyConstants => write!(f, "too many constants, the pool can only hold 256"),
+            AsmError::UnknownDirective(name) => write!(f, "unknown directive `{}`", name),
+            AsmError::InvalidData => write!(f, "invalid data"),
+            AsmError::DuplicateData(name) => write!(f, "data `{}` is defined more than once", name),
+            AsmError::UnknownData(name) => write!(f, "unknown data `{}`", name),
+            AsmError::TooMuchData => write!(f, "too much read-only data"),
+        }
     }
 }
 
@@ -41,31 +71,159 @@ impl error::Error for AsmError {
 
 }
 
+/// A range of characters in the assembler source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Line, counting starts at 1.
+    pub line: usize,
+    /// Column of the first character, counting starts at 1.
+    pub start: usize,
+    /// Column behind the last character; the same as `start` for an empty span.
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates the span of `part`, which must be a slice of the source line `line`.
+    fn of(line_number: usize, line: &str, part: &str) -> Span {
+        let offset = part.as_ptr() as usize - line.as_ptr() as usize;
+        let start = line[..offset].chars().count() + 1;
+        Span { line: line_number, start, end: start + part.chars().count() }
+    }
+}
+
+/// A single error, together with the place in the source it was found at.
+#[derive(Debug, Clone)]
+pub struct AsmIssue {
+    /// Where the error is.
+    pub span: Span,
+    /// What is wrong.
+    pub error: AsmError,
+    /// The source line the error is in, to show it.
+    pub snippet: String,
+}
+
 /// Report of failed assembly attempt.
 ///
-/// Wraps the error that occurred during assembly and supplied information where it did.
+/// Lists every error that was found in the program, ordered by their place in the source.
+/// Display shows them like rustc does, with the source line and the error underlined.
 #[derive(Debug)]
 pub struct AsmErrorReport {
     /// Name of the program that failed to assemble.
-    name: String,
-    /// Line the error occurred during assembly.
-    line: usize,
-    /// Error that occurred.
-    error: AsmError,
+    pub name: String,
+    /// All errors found.
+    pub issues: Vec<AsmIssue>,
 }
 
 impl Display for AsmErrorReport {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "assembly failed in line {} of program '{}'", self.line, self.name)
+        let count = self.issues.len();
+        write!(f, "assembly of program '{}' failed with {} error{}:",
+               self.name, count, if count == 1 { "" } else { "s" })?;
+        // all line numbers get the same width, so that the bars line up:
+        let width = self.issues.iter().map(|i| i.span.line.to_string().len()).max().unwrap_or(1);
+        for issue in &self.issues {
+            write_snippet(f, "error", &issue.error, &self.name, issue.span, &issue.snippet, width)?;
+        }
+        Ok(())
     }
 }
 
 impl error::Error for AsmErrorReport {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        Some(&self.error)
+}
+
+/// Writes a message like rustc does: the place in the source, the line, and an underline.
+///
+/// `width` is the width of the line numbers.
+fn write_snippet(f: &mut Formatter<'_>, level: &str, message: &dyn Display, name: &str,
+                 span: Span, snippet: &str, width: usize) -> std::fmt::Result {
+    let Span { line, start, end } = span;
+    // tabs are shown as four spaces, the underline must move with them:
+    let indent: usize = snippet.chars().take(start - 1)
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum();
+    write!(f, "\n{}: {}", level, message)?;
+    write!(f, "\n{:w$}--> {}:{}:{}", "", name, line, start, w = width)?;
+    write!(f, "\n{:w$} |", "", w = width)?;
+    write!(f, "\n{:>w$} | {}", line, snippet.replace('\t', "    "), w = width)?;
+    write!(f, "\n{:w$} | {:indent$}{}", "", "", "^".repeat((end - start).max(1)), w = width, indent = indent)
+}
+
+/// Something in the source that is most likely a mistake, but does not stop assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmWarning {
+    /// A label no instruction jumps to or calls.
+    UnusedLabel(String),
+    /// Instructions behind `goto`, `ret` or `fin`, without a label to reach them.
+    UnreachableCode,
+    /// A global variable that is declared, but never used.
+    UnusedVariable(String),
+    /// A global variable that is stored, but never loaded.
+    NeverLoaded(String),
+    /// A global variable that is loaded, but never stored.
+    NeverStored(String),
+    /// A local variable that is declared, but never used.
+    UnusedLocal(String),
+}
+
+impl Display for AsmWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmWarning::UnusedLabel(name) => write!(f, "label `{}` is never used", name),
+            AsmWarning::UnreachableCode => write!(f, "unreachable code"),
+            AsmWarning::UnusedVariable(name) => write!(f, "variable `{}` is never used", name),
+            AsmWarning::NeverLoaded(name) => write!(f, "variable `{}` is stored, but never loaded", name),
+            AsmWarning::NeverStored(name) => write!(f, "variable `{}` is loaded, but never stored", name),
+            AsmWarning::UnusedLocal(name) => write!(f, "local variable `{}` is never used", name),
+        }
+    }
+}
+
+/// A single warning, together with the place in the source it is about.
+#[derive(Debug, Clone)]
+pub struct AsmWarningIssue {
+    /// Where the problem is.
+    pub span: Span,
+    /// What looks wrong.
+    pub warning: AsmWarning,
+    /// The source line the problem is in, to show it.
+    pub snippet: String,
+}
+
+/// Warnings for a program that was assembled.
+///
+/// Lists every warning, ordered by their place in the source. Display shows them like
+/// `AsmErrorReport` does. It is an error itself, for those who want to treat warnings
+/// as errors.
+#[derive(Debug, Default)]
+pub struct AsmWarningReport {
+    /// Name of the program that was assembled.
+    pub name: String,
+    /// All warnings found.
+    pub warnings: Vec<AsmWarningIssue>,
+}
+
+impl AsmWarningReport {
+    /// Are there no warnings?
+    pub fn is_empty(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl Display for AsmWarningReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let count = self.warnings.len();
+        write!(f, "program '{}' has {} warning{}:", self.name, count, if count == 1 { "" } else { "s" })?;
+        let width = self.warnings.iter().map(|w| w.span.line.to_string().len()).max().unwrap_or(1);
+        for w in &self.warnings {
+            write_snippet(f, "warning", &w.warning, &self.name, w.span, &w.snippet, width)?;
+        }
+        Ok(())
     }
 }
 
+impl error::Error for AsmWarningReport {
+}
+
 /// A single instruction parsed from the line of an assembly program.
 #[derive(Debug)]
 struct AsmInstruction {
@@ -74,6 +232,8 @@ struct AsmInstruction {
     /// The number of the line the instruction was taken from, most likely
     /// from a source file. Line counting starts at 1.
     line_number: usize,
+    /// Place of the instruction in the source, from its name to the end of its argument.
+    span: Span,
     /// Opcode defining which operation is to be executed.
     opcode: u8,
     /// Arguments used for execution of the operation.
@@ -105,6 +265,17 @@ impl AsmInstruction {
 }
 
 
+/// How a global variable is used in the source, to warn about unused ones.
+#[derive(Debug, Clone, Copy)]
+struct VarUsage {
+    /// Where the variable was first seen, declared or used.
+    span: Span,
+    /// Is it used by `load`?
+    loaded: bool,
+    /// Is it used by `store`?
+    stored: bool,
+}
+
 /// A assembler program during parsing/assembling.
 #[derive(Debug)]
 struct AsmPgm {
@@ -116,18 +287,44 @@ struct AsmPgm {
     ///
     /// Used for error reporting.
     line_number: usize,
+    /// Place in the source of what is currently parsed.
+    ///
+    /// Used for error reporting; it is moved along while parts of a line are parsed.
+    span: Span,
+    /// Place in the source of the instruction that is currently parsed, from name to argument.
+    code_span: Span,
     /// Current position inside bytecode during parsing.
     ///
     /// Used to calculate the exact position an instruction will be in the bytecode.
     text_pos: usize,
-    /// The error that happened during parsing/assembling, if any.
-    error: Option<AsmError>,
+    /// The errors that happened during parsing/assembling, in order.
+    issues: Vec<AsmIssue>,
     /// A map storing label definitions by name with there position in bytecode.
     labels: HashMap<String, usize>,
     /// List holding all global variable names in order.
     vars: Vec<String>,
-    ///
+    /// List holding the names of local variables declared for the current function.
     locals: Vec<String>,
+    /// The names of local variables by the position they were declared at, for debugging.
+    local_scopes: Vec<(usize, Vec<String>)>,
+    /// Ids of the native functions the host provides, by name.
+    syscalls: HashMap<String, u8>,
+    /// Values in the constant pool, in order of their indices.
+    consts: Vec<i64>,
+    /// Number of bytes of memory the program needs.
+    memory: u32,
+    /// The read-only data segment, as it will be in the program.
+    rodata: Vec<u8>,
+    /// A map storing the entries of the read-only data by name with their position in it.
+    rodata_names: HashMap<String, usize>,
+    /// Where the labels are defined, by name, for warnings.
+    label_spans: HashMap<String, Span>,
+    /// How the global variables are used, in the same order as `vars`.
+    var_usage: Vec<VarUsage>,
+    /// Where the local variables in `locals` are declared, and if they are used.
+    local_usage: Vec<(Span, bool)>,
+    /// The warnings found during assembling.
+    warnings: Vec<AsmWarningIssue>,
 }
 
 impl AsmPgm {
@@ -140,26 +337,28 @@ impl AsmPgm {
         }
     }
 
-    /// Removes all noise from an assembler program's line.
-    fn clean_line(line: &str) -> String {
-        // Trim start and end:
-        let line = line.trim();
-        // Reduce all whitespaces to a single space (0x20):
-        ANY_WHITESPACES.replace_all(line, " ").to_string()
-    }
-
-    /// Handles a single cleaned line from an Assembly program.
-    fn parse_clean_line(&mut self, line: String) -> Result<(), AsmError> {
-        if line == "" {
+    /// Handles the code on a line from an Assembly program, after comment and label are removed.
+    ///
+    /// `code` is a slice of `line`, so that we know where the parts are in the source.
+    fn parse_code(&mut self, line: &str, code: &str) -> Result<(), AsmError> {
+        let code = code.trim();
+        if code.is_empty() {
             // empty line (or comment only) - skip
             return Ok(());
         }
-        if let Some(caps) = OP_LINE_RE.captures(&line) {
-            let opname = caps.get(1).unwrap().as_str();
-            let parm = caps.get(2).map(|m| m.as_str());
-            return self.parse_instruction(opname, parm);
-        }
-        Err(AsmError::InvalidLine)
+        let (opname, oparg) = match code.split_once(char::is_whitespace) {
+            Some((opname, oparg)) => (opname, Some(oparg.trim())),
+            None => (code, None),
+        };
+        // errors are about the argument, or where it is missing:
+        self.code_span = Span::of(self.line_number, line, code);
+        let op_span = Span::of(self.line_number, line, opname);
+        self.span = Span::of(self.line_number, line, oparg.unwrap_or(&code[opname.len()..opname.len()]));
+        self.parse_instruction(opname, oparg).inspect_err(|e| {
+            if let AsmError::UnknownInstruction(_) = e {
+                self.span = op_span;
+            }
+        })
     }
 
     /// Adds a single instruction to the end of the AsmProgram.
@@ -173,6 +372,7 @@ impl AsmPgm {
     fn push_a0_instruction(&mut self, opcode: u8) -> Result<(), AsmError> {
         let i = AsmInstruction{
             line_number: self.line_number,
+            span: self.code_span,
             opcode,
             oparg: vec![],
             pos: self.text_pos,
@@ -194,6 +394,7 @@ impl AsmPgm {
     fn push_a1_instruction(&mut self, opcode: u8, a0: u8) -> Result<(), AsmError> {
         let i = AsmInstruction{
             line_number: self.line_number,
+            span: self.code_span,
             opcode,
             oparg: vec![a0],
             pos: self.text_pos,
@@ -202,6 +403,19 @@ impl AsmPgm {
         self.push_instruction(i)
     }
 
+    /// Helper that creates an instruction with any number of bytes of oparg and pushes it.
+    fn push_an_instruction(&mut self, opcode: u8, oparg: &[u8]) -> Result<(), AsmError> {
+        let i = AsmInstruction{
+            line_number: self.line_number,
+            span: self.code_span,
+            opcode,
+            oparg: oparg.to_vec(),
+            pos: self.text_pos,
+            argument_token: None,
+        };
+        self.push_instruction(i)
+    }
+
     /*
     We have no a2-instructions at the moment
     /// Helper that creates an instruction with 1 byte of oparg and pushes it.
@@ -224,6 +438,7 @@ impl AsmPgm {
     fn push_label_instruction(&mut self, opcode: u8, label: &str) -> Result<(), AsmError> {
         let i = AsmInstruction{
             line_number: self.line_number,
+            span: self.code_span,
             opcode,
             oparg: vec![0, 0],
             pos: self.text_pos,
@@ -251,6 +466,7 @@ impl AsmPgm {
             index
         } else {
             self.vars.push(String::from(name));
+            self.var_usage.push(VarUsage { span: self.span, loaded: false, stored: false });
             self.vars.len() - 1
         };
         if index <= 0xff {
@@ -260,6 +476,109 @@ impl AsmPgm {
         }
     }
 
+    /// Returns index of a value in the constant pool.
+    ///
+    /// Values not in the pool yet are added, each value is only stored once.
+    /// Will emit AsmError::TooManyConstants if number exceeds `u8`.
+    fn get_constant_index(&mut self, value: i64) -> Result<u8, AsmError> {
+        let index = if let Some(index) = self.consts.iter().position(|&c| c == value) {
+            index
+        } else {
+            self.consts.push(value);
+            self.consts.len() - 1
+        };
+        if index <= 0xff {
+            Ok(index as u8)
+        } else {
+            Err(AsmError::TooManyConstants)
+        }
+    }
+
+    /// Parses an integer literal, like `42`, `-1`, `0xff` or `-0b101`.
+    fn parse_literal(oparg: Option<&str>) -> Result<i64, AsmError> {
+        let oparg = oparg.ok_or(AsmError::MissingArgument)?;
+        // `parse_int` handles the sign only for decimal numbers, so we do it ourselves:
+        let (negative, digits) = match oparg.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, oparg),
+        };
+        let v = parse_int::parse::<u64>(digits).or(Err(AsmError::InvalidArgument))? as i128;
+        let v = if negative { -v } else { v };
+        i64::try_from(v).or(Err(AsmError::InvalidArgument))
+    }
+
+    /// Helper that parses (and pushes) an instruction with an immediate value of a given size.
+    fn parse_immediate_instruction<T>(&mut self, opcode: u8, oparg: Option<&str>, to_bytes: fn(T) -> Vec<u8>) -> Result<(), AsmError>
+        where T: TryFrom<i64> {
+        let v = AsmPgm::parse_literal(oparg)?;
+        let v = T::try_from(v).or(Err(AsmError::InvalidArgument))?;
+        self.push_an_instruction(opcode, &to_bytes(v))
+    }
+
+    /// Helper that parses (and pushes) a `push_c`, putting the value into the constant pool.
+    fn parse_const_instruction(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
+        let v = AsmPgm::parse_literal(oparg)?;
+        let idx = self.get_constant_index(v)?;
+        self.push_a1_instruction(op::PUSH_C, idx)
+    }
+
+    /// Parses the pseudo instruction `push`, using the smallest encoding for the value.
+    ///
+    /// Values that do not fit into 32 bits are put into the constant pool.
+    fn parse_push_instruction(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
+        let v = AsmPgm::parse_literal(oparg)?;
+        let (opcode, oparg) = self.push_for(v)?;
+        self.push_an_instruction(opcode, &oparg)
+    }
+
+    /// Picks the shortest instruction that pushes a value, with its oparg.
+    ///
+    /// Values that do not fit into 32 bits are put into the constant pool.
+    fn push_for(&mut self, v: i64) -> Result<(u8, Vec<u8>), AsmError> {
+        Ok(if let Ok(v) = u8::try_from(v) {
+            (op::PUSH_U8, vec![v])
+        } else if let Ok(v) = i8::try_from(v) {
+            (op::PUSH_I8, v.to_be_bytes().to_vec())
+        } else if let Ok(v) = i16::try_from(v) {
+            (op::PUSH_I16, v.to_be_bytes().to_vec())
+        } else if let Ok(v) = i32::try_from(v) {
+            (op::PUSH_I32, v.to_be_bytes().to_vec())
+        } else {
+            (op::PUSH_C, vec![self.get_constant_index(v)?])
+        })
+    }
+
+    /// Declares a global variable, so that it gets the next free index.
+    ///
+    /// Variables are also declared implicitly by their first use; this allows to control
+    /// the order of the indices.
+    fn parse_global_declaration(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
+        let name = oparg.ok_or(AsmError::MissingArgument)?;
+        if !VALID_LABEL.is_match(name) {
+            return Err(AsmError::InvalidVariable(String::from(name)));
+        }
+        self.get_variable_index(name)?;
+        Ok(())
+    }
+
+    /// Declares the number of bytes of memory the program needs.
+    ///
+    /// If there are several declarations, the largest one counts.
+    fn parse_memory_declaration(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
+        let oparg = oparg.ok_or(AsmError::MissingArgument)?;
+        let size = parse_int::parse::<u32>(oparg).or(Err(AsmError::InvalidArgument))?;
+        self.memory = self.memory.max(size);
+        Ok(())
+    }
+
+    /// Remembers the local variables declared at the current position, for the debug information.
+    fn record_locals(&mut self) {
+        match self.local_scopes.last_mut() {
+            Some((pos, names)) if *pos == self.text_pos => *names = self.locals.clone(),
+            _ => self.local_scopes.push((self.text_pos, self.locals.clone())),
+        }
+    }
+
     fn parse_local_declaration(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
         if let Some(vname) = oparg {
             if VALID_LABEL.is_match(vname) {
@@ -269,13 +588,17 @@ impl AsmPgm {
                     Err(AsmError::TooManyVariables)
                 } else {
                     self.locals.push(String::from(vname));
+                    self.local_usage.push((self.span, false));
+                    self.record_locals();
                     Ok(())
                 }
             } else {
                 Err(AsmError::InvalidVariable(String::from(vname)))
             }
         } else {
+            self.check_locals();
             self.locals.clear();
+            self.record_locals();
             Ok(())
         }
     }
@@ -286,6 +609,7 @@ impl AsmPgm {
             Err(AsmError::InvalidVariable(String::from(name)))
         } else {
             if let Some(index) = self.locals.iter().position(|r| r == name) {
+                self.local_usage[index].1 = true;
                 self.push_a1_instruction(opcode, index as u8)
             } else {
                 Err(AsmError::UnknownVariable(String::from(name)))
@@ -293,55 +617,178 @@ impl AsmPgm {
         }
     }
 
+    /// Helper that parses (and pushes) a `load_ro` from an entry in the read-only data by name.
+    ///
+    /// Like labels, data can be used before it is defined, so the position is filled in later.
+    fn parse_rodata_instruction(&mut self, opcode: u8, oparg: Option<&str>) -> Result<(), AsmError> {
+        let name = oparg.ok_or(AsmError::MissingArgument)?;
+        if !VALID_LABEL.is_match(name) {
+            return Err(AsmError::InvalidLabel(String::from(name)));
+        }
+        let i = AsmInstruction{
+            line_number: self.line_number,
+            span: self.code_span,
+            opcode,
+            oparg: vec![0, 0],
+            pos: self.text_pos,
+            argument_token: Some(String::from(name)),
+        };
+        self.push_instruction(i)
+    }
+
+    /// Parses the value of a `.string` directive: text in double quotes.
+    ///
+    /// The escapes `\\`, `\"`, `\n`, `\t` and `\0` are supported. After the closing quote,
+    /// there can only be a comment.
+    fn parse_string_literal(values: &str) -> Result<Vec<u8>, AsmError> {
+        let mut chars = values.trim().strip_prefix('"').ok_or(AsmError::InvalidData)?.chars();
+        let mut s = String::new();
+        loop {
+            match chars.next().ok_or(AsmError::InvalidData)? {
+                '"' => break,
+                '\\' => s.push(match chars.next().ok_or(AsmError::InvalidData)? {
+                    'n' => '\n',
+                    't' => '\t',
+                    '0' => '\0',
+                    c @ ('\\' | '"') => c,
+                    _ => return Err(AsmError::InvalidData),
+                }),
+                c => s.push(c),
+            }
+        }
+        let rest = AsmPgm::remove_comment(chars.as_str());
+        if rest.trim().is_empty() {
+            Ok(s.into_bytes())
+        } else {
+            Err(AsmError::InvalidData)
+        }
+    }
+
+    /// Parses the comma separated values of a `.data` or `.bytes` directive.
+    ///
+    /// `values` is a slice of the source line `line`; values wider than `width` bytes fail.
+    fn parse_data_values(&mut self, line: &str, values: &str, width: usize) -> Result<Vec<u8>, AsmError> {
+        let values = AsmPgm::remove_comment(values).trim();
+        let mut data = vec![];
+        // an empty list gives an empty table:
+        for v in values.split(',').filter(|_| !values.is_empty()) {
+            let v = v.trim();
+            self.span = Span::of(self.line_number, line, v);
+            let v = AsmPgm::parse_literal(Some(v))?;
+            if width == 1 {
+                data.push(u8::try_from(v).or(Err(AsmError::InvalidArgument))?);
+            } else {
+                data.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+        self.span = Span::of(self.line_number, line, values);
+        Ok(data)
+    }
+
+    /// Parses a directive defining an entry in the read-only data segment.
+    ///
+    /// Directives look like `.data name: 1, 2, 3` (a table of i64 values), `.bytes name: 1, 2, 3`
+    /// (a table of bytes), or `.string name: "text"` (the bytes of a string).
+    fn parse_data_directive(&mut self, line: &str) -> Result<(), AsmError> {
+        let code = line.trim();
+        self.span = Span::of(self.line_number, line, code);
+        let (directive, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+        let (name, values) = rest.split_once(':').ok_or(AsmError::InvalidData)?;
+        let name = name.trim();
+        let name_span = Span::of(self.line_number, line, name);
+        self.span = name_span;
+        if !VALID_LABEL.is_match(name) {
+            return Err(AsmError::InvalidLabel(String::from(name)));
+        }
+        let (width, data) = match directive {
+            ".data" => (8, self.parse_data_values(line, values, 8)?),
+            ".bytes" => (1, self.parse_data_values(line, values, 1)?),
+            ".string" => {
+                self.span = Span::of(self.line_number, line, values.trim());
+                (1, AsmPgm::parse_string_literal(values)?)
+            },
+            _ => {
+                self.span = Span::of(self.line_number, line, directive);
+                return Err(AsmError::UnknownDirective(String::from(directive)));
+            },
+        };
+        let count = u16::try_from(data.len() / width).or(Err(AsmError::TooMuchData))?;
+        // `load_ro` can only reach entries starting at positions that fit into u16:
+        self.span = name_span;
+        let offset = self.rodata.len();
+        if offset > u16::MAX as usize {
+            return Err(AsmError::TooMuchData);
+        }
+        if self.rodata_names.insert(String::from(name), offset).is_some() {
+            return Err(AsmError::DuplicateData(String::from(name)));
+        }
+        self.rodata.push(width as u8);
+        self.rodata.extend_from_slice(&count.to_be_bytes());
+        self.rodata.extend(data);
+        Ok(())
+    }
+
+    /// Helper that parses (and pushes) a call to a native function, given by name or numeric id.
+    fn parse_syscall_instruction(&mut self, oparg: Option<&str>) -> Result<(), AsmError> {
+        let name = oparg.ok_or(AsmError::MissingArgument)?;
+        let id = if let Some(&id) = self.syscalls.get(name) {
+            id
+        } else if let Ok(id) = parse_int::parse::<u8>(name) {
+            id
+        } else {
+            return Err(AsmError::UnknownSyscall(String::from(name)));
+        };
+        self.push_a1_instruction(op::SYSCALL, id)
+    }
+
+    /// Helper that parses (and pushes) an instruction using a global variable by name.
+    fn parse_global_instruction(&mut self, opcode: u8, oparg: Option<&str>) -> Result<(), AsmError> {
+        let name = oparg.ok_or(AsmError::MissingArgument)?;
+        if !VALID_LABEL.is_match(name) {
+            return Err(AsmError::InvalidVariable(String::from(name)));
+        }
+        let ix = self.get_variable_index(name)?;
+        let usage = &mut self.var_usage[ix as usize];
+        match opcode {
+            op::LOAD => usage.loaded = true,
+            op::STORE => usage.stored = true,
+            _ => {},
+        }
+        self.push_a1_instruction(opcode, ix)
+    }
+
     /// Handles a single instruction of opcode an optional oparg parsed from Assembly file.
+    ///
+    /// Instructions are looked up in the opcode table `op::OPS`; the kind of oparg decides
+    /// how the argument is parsed.
     fn parse_instruction(&mut self, opname: &str, oparg: Option<&str>) -> Result<(), AsmError> {
+        // declarations are no instructions:
         match opname {
-            "nop" => self.parse_a0_instruction(op::NOP, oparg),
-            "fin" => self.parse_a0_instruction(op::FIN, oparg),
-            "pop" => self.parse_a0_instruction(op::POP, oparg),
-            "dup" => self.parse_a0_instruction(op::DUP, oparg),
-            "out" => self.parse_a0_instruction(op::OUT, oparg),
-            "add" => self.parse_a0_instruction(op::ADD, oparg),
-            "sub" => self.parse_a0_instruction(op::SUB, oparg),
-            "mul" => self.parse_a0_instruction(op::MUL, oparg),
-            "div" => self.parse_a0_instruction(op::DIV, oparg),
-            "mod" => self.parse_a0_instruction(op::MOD, oparg),
-            "rot" => self.parse_a0_instruction(op::ROT, oparg),
-            "push_u8" => {
+            "var" => return self.parse_global_declaration(oparg),
+            "local" => return self.parse_local_declaration(oparg),
+            "memory" => return self.parse_memory_declaration(oparg),
+            // `push` is no real instruction, it picks one of the push instructions:
+            "push" => return self.parse_push_instruction(oparg),
+            _ => {},
+        }
+        let info = op::by_mnemonic(opname)
+            .ok_or_else(|| AsmError::UnknownInstruction(String::from(opname)))?;
+        match info.oparg {
+            Oparg::None => self.parse_a0_instruction(info.opcode, oparg),
+            Oparg::U8 => {
                 let oparg = oparg.ok_or(AsmError::MissingArgument)?;
                 let v = parse_int::parse::<u8>(oparg).or(Err(AsmError::InvalidArgument))?;
-                self.push_a1_instruction(op::PUSH_U8, v)
+                self.push_a1_instruction(info.opcode, v)
             },
-            "goto" => self.parse_label_instruction(op::GOTO, oparg),
-            "ifeq" => self.parse_label_instruction(op::IFEQ, oparg),
-            "ifne" => self.parse_label_instruction(op::IFNE, oparg),
-            "iflt" => self.parse_label_instruction(op::IFLT, oparg),
-            "ifle" => self.parse_label_instruction(op::IFLE, oparg),
-            "ifgt" => self.parse_label_instruction(op::IFGT, oparg),
-            "ifge" => self.parse_label_instruction(op::IFGE, oparg),
-            "load" => {
-                let name = oparg.ok_or(AsmError::MissingArgument)?;
-                if !VALID_LABEL.is_match(name) {
-                    return Err(AsmError::InvalidVariable(String::from(name)));
-                }
-                let ix = self.get_variable_index(name)?;
-                self.push_a1_instruction(op::LOAD, ix)
-            }
-            "store" => {
-                let name = oparg.ok_or(AsmError::MissingArgument)?;
-                if !VALID_LABEL.is_match(name) {
-                    return Err(AsmError::InvalidVariable(String::from(name)));
-                }
-                let ix = self.get_variable_index(name)?;
-                self.push_a1_instruction(op::STORE, ix)
-            }
-            "ret" => self.parse_a0_instruction(op::RET, oparg),
-            "call" => self.parse_label_instruction(op::CALL, oparg),
-            "local" => self.parse_local_declaration(oparg),
-            "load_l" => self.parse_local_instruction(op::LOAD_L, oparg),
-            "store_l" => self.parse_local_instruction(op::STORE_L, oparg),
-            "swap_l" => self.parse_local_instruction(op::SWAP_L, oparg),
-            _ => Err(AsmError::UnknownInstruction(String::from(opname)))
+            Oparg::I8 => self.parse_immediate_instruction(info.opcode, oparg, |v: i8| v.to_be_bytes().to_vec()),
+            Oparg::I16 => self.parse_immediate_instruction(info.opcode, oparg, |v: i16| v.to_be_bytes().to_vec()),
+            Oparg::I32 => self.parse_immediate_instruction(info.opcode, oparg, |v: i32| v.to_be_bytes().to_vec()),
+            Oparg::Const => self.parse_const_instruction(oparg),
+            Oparg::RoData => self.parse_rodata_instruction(info.opcode, oparg),
+            Oparg::Global => self.parse_global_instruction(info.opcode, oparg),
+            Oparg::Local => self.parse_local_instruction(info.opcode, oparg),
+            Oparg::Syscall => self.parse_syscall_instruction(oparg),
+            Oparg::Jump => self.parse_label_instruction(info.opcode, oparg),
         }
     }
 
@@ -359,12 +806,14 @@ impl AsmPgm {
     /// label definition.
     fn parse_label_definition<'a>(&mut self, line: &'a str) -> Result<&'a str, AsmError> {
         if let Some((label, rest)) = line.split_once(":") {
-            let label = label.trim_start();
+            let label = label.trim();
+            self.span = Span::of(self.line_number, line, label);
             if VALID_LABEL.is_match(label) {
                 if self.labels.contains_key(label) {
                     Err(AsmError::DuplicateLabel(String::from(label)))
                 } else {
                     self.labels.insert(String::from(label), self.text_pos);
+                    self.label_spans.insert(String::from(label), self.span);
                     Ok(rest)
                 }
             } else {
@@ -375,20 +824,43 @@ impl AsmPgm {
         }
     }
 
+    /// Parses a single line of source code.
+    fn parse_line(&mut self, line: &str) -> Result<(), AsmError> {
+        // directives for the read-only data have their own syntax (strings can hold `#`):
+        if line.trim_start().starts_with('.') {
+            return self.parse_data_directive(line);
+        }
+        let code = AsmPgm::remove_comment(line);
+        let code = match self.parse_label_definition(code) {
+            Ok(rest) => rest,
+            Err(e) => {
+                // the instruction behind a bad label can still be checked:
+                self.add_issue(e);
+                code.split_once(':').map_or(code, |(_, rest)| rest)
+            },
+        };
+        self.parse_code(line, code)
+    }
+
+    /// Records an error at the current place in the source.
+    fn add_issue(&mut self, error: AsmError) {
+        self.issues.push(AsmIssue { span: self.span, error, snippet: String::new() });
+    }
+
     /// Parses source code and fills AsmPgm with instructions from it.
     ///
-    /// If there is an error, parsing is aborted, and the error is stored.
-    fn parse(&mut self, content: &str) -> Result<(), AsmError> {
+    /// A line with an error is skipped after the error is stored, so that we find the errors
+    /// in all the other lines, too.
+    fn parse(&mut self, content: &str) {
         // read the source, one line at a time, adding instructions:
         for (n, line) in content.lines().enumerate() {
             // File lines start counting at 1:
             self.line_number = n + 1;
-            let line = AsmPgm::remove_comment(line);
-            let line = self.parse_label_definition(line)?;
-            let line = AsmPgm::clean_line(line);
-            self.parse_clean_line(line)?;
+            self.span = Span::of(self.line_number, line, line);
+            if let Err(e) = self.parse_line(line) {
+                self.add_issue(e);
+            }
         }
-        Ok(())
     }
 
     /// Update those instructions that need post processing.
@@ -397,81 +869,756 @@ impl AsmPgm {
     /// source file has been parsed. Those will be updated in this "second run".
     /// Opargs have been filled with placeholders before. The number of bytes should not be
     /// altered, because jump destinations are calculated from the number of bytes.
-    fn update_instructions(&mut self) -> Result<(), AsmError> {
+    fn update_instructions(&mut self) {
+        let mut issues = vec![];
         for i in &mut self.instructions {
-            self.line_number = i.line_number;
             if let Some(label) = &i.argument_token {
-                if let Some(&dest) = self.labels.get(label) {
+                let error = if i.opcode == op::LOAD_RO {
+                    // this is no label, but the name of an entry in the read-only data:
+                    match self.rodata_names.get(label) {
+                        Some(&offset) => {
+                            i.oparg[..2].copy_from_slice(&(offset as u16).to_be_bytes());
+                            continue;
+                        },
+                        None => AsmError::UnknownData(String::from(label)),
+                    }
+                } else if let Some(&dest) = self.labels.get(label) {
                     let src = i.pos + i.size();
                     if src.abs_diff(dest) > i16::MAX as usize {
-                        return Err(AsmError::JumpTooLong);
+                        AsmError::JumpTooLong
+                    } else {
+                        let delta = (dest as i64 - src as i64) as i16;
+                        i.oparg[..2].copy_from_slice(&delta.to_be_bytes()[..2]);
+                        continue;
                     }
-                    let delta = (dest as i64 - src as i64) as i16;
-                    i.oparg[..2].copy_from_slice(&delta.to_be_bytes()[..2]);
                 } else {
-                    return Err(AsmError::UnknownLabel(String::from(label)));
+                    AsmError::UnknownLabel(String::from(label))
+                };
+                // the argument is at the end of the instruction:
+                let span = Span { start: i.span.end - label.chars().count(), ..i.span };
+                issues.push(AsmIssue { span, error, snippet: String::new() });
+            }
+        }
+        self.issues.extend(issues);
+    }
+
+    /// Records a warning.
+    fn add_warning(&mut self, span: Span, warning: AsmWarning) {
+        self.warnings.push(AsmWarningIssue { span, warning, snippet: String::new() });
+    }
+
+    /// Warns about the local variables of the current function that were never used.
+    fn check_locals(&mut self) {
+        for (ix, (span, used)) in self.local_usage.clone().into_iter().enumerate() {
+            if !used {
+                self.add_warning(span, AsmWarning::UnusedLocal(self.locals[ix].clone()));
+            }
+        }
+        self.local_usage.clear();
+    }
+
+    /// Looks for things in the complete program that are probably mistakes.
+    ///
+    /// Labels at the very start of the program are not reported as unused, they name the
+    /// entry point.
+    fn find_warnings(&mut self) {
+        self.check_locals();
+        // labels used by jumps and calls (`load_ro` uses names of data, not labels):
+        let used: HashSet<&String> = self.instructions.iter()
+            .filter(|i| i.opcode != op::LOAD_RO)
+            .filter_map(|i| i.argument_token.as_ref())
+            .collect();
+        let mut warnings = vec![];
+        for (name, &pos) in &self.labels {
+            if pos != 0 && !used.contains(name) {
+                warnings.push((self.label_spans[name], AsmWarning::UnusedLabel(name.clone())));
+            }
+        }
+        // code can only be reached from the instruction before, or by a label:
+        let targets: HashSet<usize> = self.labels.values().copied().collect();
+        let mut falls_through = true;
+        let mut reported = false;
+        for i in &self.instructions {
+            if falls_through || targets.contains(&i.pos) {
+                reported = false;
+                let flow = op::info(i.opcode).map(|info| info.flow);
+                falls_through = !matches!(flow, Some(Flow::Goto | Flow::Return | Flow::Fin));
+            } else if !reported {
+                // only report the first instruction of unreachable code:
+                warnings.push((i.span, AsmWarning::UnreachableCode));
+                reported = true;
+            }
+        }
+        for (name, usage) in self.vars.iter().zip(&self.var_usage) {
+            let warning = match (usage.loaded, usage.stored) {
+                (false, false) => AsmWarning::UnusedVariable(name.clone()),
+                (false, true) => AsmWarning::NeverLoaded(name.clone()),
+                (true, false) => AsmWarning::NeverStored(name.clone()),
+                (true, true) => continue,
+            };
+            warnings.push((usage.span, warning));
+        }
+        for (span, warning) in warnings {
+            self.add_warning(span, warning);
+        }
+    }
+
+    /// Positions in bytecode that are jumped to, or called.
+    fn targets(&self) -> HashSet<usize> {
+        self.labels.values().copied().collect()
+    }
+
+    /// Value an instruction pushes, if it pushes a constant.
+    fn constant(&self, i: &AsmInstruction) -> Option<i64> {
+        let a = &i.oparg;
+        match i.opcode {
+            op::PUSH_U8 => Some(a[0] as i64),
+            op::PUSH_I8 => Some(a[0] as i8 as i64),
+            op::PUSH_I16 => Some(i16::from_be_bytes([a[0], a[1]]) as i64),
+            op::PUSH_I32 => Some(i32::from_be_bytes([a[0], a[1], a[2], a[3]]) as i64),
+            op::PUSH_C => self.consts.get(a[0] as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /// Gives the instructions new positions after some were changed or removed.
+    ///
+    /// Labels and the scopes of local variables move along; if their instruction was
+    /// removed, they move to the one behind it.
+    fn relocate(&mut self) {
+        let old: Vec<usize> = self.instructions.iter().map(|i| i.pos).collect();
+        let mut pos = 0;
+        for i in &mut self.instructions {
+            i.pos = pos;
+            pos += i.size();
+        }
+        self.text_pos = pos;
+        let instructions = &self.instructions;
+        let moved = |p: &mut usize| *p = instructions.get(old.partition_point(|&o| o < *p)).map_or(pos, |i| i.pos);
+        self.labels.values_mut().for_each(&moved);
+        self.local_scopes.iter_mut().for_each(|(p, _)| moved(p));
+    }
+
+    /// Replaces arithmetic on constants with a push of the result.
+    ///
+    /// Only results that are the same in every overflow mode are calculated, and only if no
+    /// jump leads into the middle of the instructions replaced.
+    fn fold_constants(&mut self) -> bool {
+        let targets = self.targets();
+        let mut changed = false;
+        let mut ix = 0;
+        while ix < self.instructions.len() {
+            let is = &self.instructions[ix..];
+            let inside = |n: usize| (1..n).all(|k| is.get(k).is_some_and(|i| !targets.contains(&i.pos)));
+            let folded = match is {
+                [a, c, ..] if c.opcode == op::NOT && inside(2) => self.constant(a).map(|a| (!a, 2)),
+                [a, b, c, ..] if inside(3) => match (self.constant(a), self.constant(b)) {
+                    (Some(a), Some(b)) => fold(c.opcode, a, b).map(|v| (v, 3)),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match folded.map(|(v, n)| (self.push_for(v), n)) {
+                Some((Ok((opcode, oparg)), n)) => {
+                    let i = &mut self.instructions[ix];
+                    i.opcode = opcode;
+                    i.oparg = oparg;
+                    self.instructions.drain(ix + 1..ix + n);
+                    changed = true;
+                    // the result might be folded with the constant before:
+                    ix = ix.saturating_sub(1);
+                },
+                _ => ix += 1,
+            }
+        }
+        changed
+    }
+
+    /// Removes `nop`s, jumps to the next instruction, and constants pushed only to be popped.
+    fn remove_useless(&mut self) -> bool {
+        let targets = self.targets();
+        let is = &self.instructions;
+        let mut keep = vec![true; is.len()];
+        let mut ix = 0;
+        while ix < is.len() {
+            let i = &is[ix];
+            let next = i.pos + i.size();
+            // a constant is pushed without any checks that could fail, `pop` takes it away again:
+            let pushes = self.constant(i).is_some();
+            let jumps_next = i.opcode == op::GOTO
+                && i.argument_token.as_ref().and_then(|l| self.labels.get(l)) == Some(&next);
+            if i.opcode == op::NOP || jumps_next {
+                keep[ix] = false;
+            } else if pushes && is.get(ix + 1).is_some_and(|p| p.opcode == op::POP && !targets.contains(&p.pos)) {
+                keep[ix] = false;
+                keep[ix + 1] = false;
+                ix += 1;
+            }
+            ix += 1;
+        }
+        self.retain(&keep)
+    }
+
+    /// Lets jumps that lead to a `goto` jump to its destination directly.
+    ///
+    /// Code only gets shorter after this, so a destination that is in reach now stays so.
+    fn thread_jumps(&mut self) -> bool {
+        let at: HashMap<usize, &AsmInstruction> = self.instructions.iter().map(|i| (i.pos, i)).collect();
+        // where a label leads, if there is a `goto` at it:
+        let forward = |label: &String| {
+            let i = at.get(self.labels.get(label)?)?;
+            i.argument_token.clone().filter(|_| i.opcode == op::GOTO)
+        };
+        let mut changes = vec![];
+        for (ix, i) in self.instructions.iter().enumerate() {
+            let jumps = matches!(op::info(i.opcode).map(|info| info.flow), Some(Flow::Goto | Flow::Branch));
+            let Some(label) = i.argument_token.as_ref().filter(|_| jumps) else {
+                continue;
+            };
+            // follow the chain of `goto`s, but not around in circles, and not further
+            // than a jump can reach:
+            let src = i.pos + i.size();
+            let reaches = |label: &String| self.labels.get(label).is_some_and(|&d| src.abs_diff(d) <= i16::MAX as usize);
+            let mut seen = HashSet::from([label.clone()]);
+            let mut dest = label.clone();
+            while let Some(next) = forward(&dest) {
+                if !reaches(&next) || !seen.insert(next.clone()) {
+                    break;
                 }
+                dest = next;
+            }
+            if &dest != label {
+                changes.push((ix, dest));
             }
         }
-        Ok(())
+        let changed = !changes.is_empty();
+        for (ix, dest) in changes {
+            self.instructions[ix].argument_token = Some(dest);
+        }
+        changed
     }
 
-    fn process(&mut self, content: &str) -> Result<(), AsmError> {
-        // Go over complete source, extracting instructions. Some will have their opargs
-        // left empty (with placeholders).
-        self.parse(content)?;
-        self.update_instructions()
+    /// Removes instructions that cannot be reached from the start of the program.
+    fn remove_unreachable(&mut self) -> bool {
+        let is = &self.instructions;
+        let at: HashMap<usize, usize> = is.iter().enumerate().map(|(ix, i)| (i.pos, ix)).collect();
+        let mut reachable = vec![false; is.len()];
+        let mut todo = if is.is_empty() { vec![] } else { vec![0] };
+        while let Some(ix) = todo.pop() {
+            if reachable[ix] {
+                continue;
+            }
+            reachable[ix] = true;
+            let i = &is[ix];
+            let info = op::info(i.opcode).unwrap();
+            if matches!(info.flow, Flow::Next | Flow::Branch | Flow::Call) && ix + 1 < is.len() {
+                todo.push(ix + 1);
+            }
+            if info.oparg == Oparg::Jump {
+                let dest = i.argument_token.as_ref().and_then(|l| self.labels.get(l)).and_then(|p| at.get(p));
+                todo.extend(dest);
+            }
+        }
+        self.retain(&reachable)
+    }
+
+    /// Keeps only the instructions that are marked; returns if any were removed.
+    fn retain(&mut self, keep: &[bool]) -> bool {
+        if keep.iter().all(|&k| k) {
+            return false;
+        }
+        let mut keep = keep.iter();
+        self.instructions.retain(|_| *keep.next().unwrap());
+        true
+    }
+
+    /// Makes the program smaller and faster, without changing what it does.
+    ///
+    /// Constant arithmetic is calculated, useless instructions are removed, jumps to jumps
+    /// are shortened, and code that can never be reached is deleted. This is repeated until
+    /// nothing changes any more, as one thing often makes another possible. Afterwards, the
+    /// jump destinations must be updated.
+    fn optimize(&mut self) {
+        let passes: [fn(&mut AsmPgm) -> bool; 4] = [
+            AsmPgm::fold_constants,
+            AsmPgm::remove_useless,
+            AsmPgm::thread_jumps,
+            AsmPgm::remove_unreachable,
+        ];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pass in passes {
+                if pass(self) {
+                    self.relocate();
+                    changed = true;
+                }
+            }
+        }
+        // folding can leave constants behind that are no longer used; the pool is rebuilt
+        // in order of first use, as the assembler does it:
+        let mut consts = vec![];
+        for i in self.instructions.iter_mut().filter(|i| i.opcode == op::PUSH_C) {
+            let value = self.consts[i.oparg[0] as usize];
+            let ix = consts.iter().position(|&c| c == value).unwrap_or_else(|| {
+                consts.push(value);
+                consts.len() - 1
+            });
+            i.oparg[0] = ix as u8;
+        }
+        self.consts = consts;
     }
 
     /// Process assembly source code. Must be used with "empty" AsmPgm.
-    fn process_assembly(&mut self, content: &str) {
-        // this function is just a wrapper around `process()`, so that I can use the
-        // return magic and don't need to write the error check twice.
-        if let Err(e) = self.process(content) {
-            self.error = Some(e);
+    ///
+    /// With `optimize`, the program is optimized after it was checked; warnings are still
+    /// about the source as it is written.
+    fn process_assembly(&mut self, content: &str, optimize: bool) {
+        // Go over complete source, extracting instructions. Some will have their opargs
+        // left empty (with placeholders).
+        self.parse(content);
+        self.update_instructions();
+        self.find_warnings();
+        if optimize && self.issues.is_empty() {
+            self.optimize();
+            // instructions moved, jump distances must be calculated again:
+            self.update_instructions();
         }
+        // errors and warnings are shown with the source line they are in:
+        let lines: Vec<&str> = content.lines().collect();
+        for issue in &mut self.issues {
+            issue.snippet = String::from(lines[issue.span.line - 1]);
+        }
+        self.issues.sort_by_key(|i| (i.span.line, i.span.start));
+        for w in &mut self.warnings {
+            w.snippet = String::from(lines[w.span.line - 1]);
+        }
+        self.warnings.sort_by_key(|w| (w.span.line, w.span.start));
     }
 
     /// Convert parsed assembly source to runnable program (or error report).
-    fn to_program(&self) -> Result<Pgm, AsmErrorReport> {
-        if let Some(e) = &self.error {
+    fn to_program(&self) -> Result<Pgm<'static>, AsmErrorReport> {
+        if !self.issues.is_empty() {
             // Assembling failed:
             Err(AsmErrorReport{
                 name: self.name.clone(),
-                line: self.line_number,
-                error: e.clone(),
+                issues: self.issues.clone(),
             })
         } else {
             // Assembling succeeded, return a Pgm instance:
             let mut text: Vec<u8> = vec![];
+            let mut debug = DebugInfo { file: self.name.clone(), ..Default::default() };
             for i in &self.instructions {
                 text.push(i.opcode);
                 text.extend(&i.oparg);
+                debug.add_line(i.pos, i.line_number);
             }
+            // labels that are called are the start of functions:
+            let called: HashSet<&String> = self.instructions.iter()
+                .filter(|i| i.opcode == op::CALL)
+                .filter_map(|i| i.argument_token.as_ref())
+                .collect();
+            debug.symbols = self.labels.iter()
+                .map(|(name, &pos)| Symbol { name: name.clone(), pos, function: called.contains(name) })
+                .collect();
+            debug.symbols.sort_by(|a, b| (a.pos, &a.name).cmp(&(b.pos, &b.name)));
+            debug.vars = self.vars.clone();
+            debug.locals = self.local_scopes.clone();
             Ok(Pgm{
-                name: self.name.clone(),
-                text,
+                name: self.name.clone().into(),
+                text: text.into(),
                 vars: self.vars.len() as u8,
+                memory: self.memory,
+                rodata: self.rodata.clone().into(),
+                consts: self.consts.iter().flat_map(|c| c.to_be_bytes()).collect::<Vec<u8>>().into(),
+                stack: None,
+                debug: Some(debug),
             })
         }
     }
 }
 
 /// Parse assembly source code and turn it into a runnable program (or create report).
-pub fn assemble(name: &str, content: &str) -> Result<Pgm, AsmErrorReport> {
+///
+/// `syscalls` maps the names of the native functions the host provides to their ids,
+/// so that programs can use them like `syscall led_on`.
+pub fn assemble(name: &str, content: &str, syscalls: &[(&str, u8)]) -> Result<Pgm<'static>, AsmErrorReport> {
+    assemble_with_warnings(name, content, syscalls, false).map(|(pgm, _)| pgm)
+}
+
+/// Like `assemble`, but also reports warnings about things in the source that look wrong.
+///
+/// Warnings are given for labels that are never used, code that cannot be reached,
+/// global variables that are never loaded or never stored, and local variables that are
+/// never used.
+///
+/// With `optimize`, constant arithmetic is folded, `nop`s and values that are pushed only
+/// to be popped are removed, jumps to `goto`s jump to its destination directly, and
+/// unreachable code is deleted. Without it, the program is exactly what the source says.
+pub fn assemble_with_warnings(name: &str, content: &str, syscalls: &[(&str, u8)], optimize: bool)
+        -> Result<(Pgm<'static>, AsmWarningReport), AsmErrorReport> {
     // create a new, clean instance to fill during parsing:
     let mut asm_pgm = AsmPgm {
         name: String::from(name),
         instructions: vec![],
         line_number: 0,
+        span: Span { line: 0, start: 1, end: 1 },
+        code_span: Span { line: 0, start: 1, end: 1 },
         text_pos: 0,
-        error: None,
+        issues: vec![],
         labels: Default::default(),
         vars: Default::default(),
         locals: Default::default(),
+        local_scopes: Default::default(),
+        syscalls: syscalls.iter().map(|&(name, id)| (String::from(name), id)).collect(),
+        consts: Default::default(),
+        memory: 0,
+        rodata: vec![],
+        rodata_names: Default::default(),
+        label_spans: Default::default(),
+        var_usage: vec![],
+        local_usage: vec![],
+        warnings: vec![],
     };
     // evaluate the source code:
-    asm_pgm.process_assembly(content);
+    asm_pgm.process_assembly(content, optimize);
     // convert to Pgm instance if successful, or to Error Report, if assembly failed:
-    asm_pgm.to_program()
+    let pgm = asm_pgm.to_program()?;
+    Ok((pgm, AsmWarningReport { name: String::from(name), warnings: asm_pgm.warnings }))
+}
+
+/// Calculates what an instruction does with two constants, exactly like the VM.
+///
+/// Gives `None` for instructions that cannot be calculated, for results that depend on
+/// the VM's overflow mode, and for division by zero, which must fail when running.
+fn fold(opcode: u8, a: i64, b: i64) -> Option<i64> {
+    match opcode {
+        op::ADD => a.checked_add(b),
+        op::SUB => a.checked_sub(b),
+        op::MUL => a.checked_mul(b),
+        op::DIV => a.checked_div(b),
+        op::MOD if b != 0 => Some(a.wrapping_rem(b)),
+        op::AND => Some(a & b),
+        op::OR => Some(a | b),
+        op::XOR => Some(a ^ b),
+        op::SHL => Some(if (0..64).contains(&b) { a << b } else { 0 }),
+        op::SHR => Some(if (0..64).contains(&b) { ((a as u64) >> b) as i64 } else { 0 }),
+        op::SAR => Some(if (0..64).contains(&b) { a >> b } else { a >> 63 }),
+        _ => None,
+    }
+}
+
+/// Turns a program's bytecode back into assembler source.
+///
+/// Jump destinations get synthesized labels (`L` followed by the position), global variables
+/// are declared with `var`, local variables with `local` and memory with `memory`. Entries of
+/// the read-only data get synthesized names (`D` followed by the position), so that the result
+/// assembles to the identical program. `push_c` is written with the constant's value; the
+/// assembler rebuilds the constant pool in order of first use, as it did when the program was
+/// assembled. Every instruction is annotated with its position in the bytecode.
+/// Bytes that cannot be decoded are listed in comments; only programs that pass
+/// `verify::verify` round-trip exactly.
+pub fn disassemble(pgm: &Pgm) -> String {
+    let text = &pgm.text;
+    // First run: decode instructions and collect what needs declaring.
+    let mut decoded: Vec<(usize, &op::OpInfo, &[u8])> = vec![];
+    let mut locals = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let info = match op::info(text[pos]) {
+            Some(info) if pos + 1 + info.oparg.size() <= text.len() => info,
+            _ => break,
+        };
+        let size = info.oparg.size();
+        let oparg = &text[pos + 1..pos + 1 + size];
+        if info.oparg == Oparg::Local {
+            locals = locals.max(oparg[0] as usize + 1);
+        }
+        decoded.push((pos, info, oparg));
+        pos += 1 + size;
+    }
+    let starts: HashSet<usize> = decoded.iter().map(|d| d.0).collect();
+    let dest = |pos: usize, oparg: &[u8]| {
+        let delta = i16::from_be_bytes([oparg[0], oparg[1]]);
+        (pos + 3) as i64 + delta as i64
+    };
+    let labels: HashSet<usize> = decoded.iter()
+        .filter(|(_, info, _)| info.oparg == Oparg::Jump)
+        .map(|&(pos, _, oparg)| dest(pos, oparg))
+        .filter(|&d| d >= 0 && starts.contains(&(d as usize)))
+        .map(|d| d as usize)
+        .collect();
+
+    // Second run: write the source.
+    let mut out = String::new();
+    writeln!(out, "# Disassembly of program '{}'.", pgm.name).unwrap();
+    for ix in 0..pgm.vars {
+        writeln!(out, "var g{}", ix).unwrap();
+    }
+    if pgm.memory != 0 {
+        writeln!(out, "memory {}", pgm.memory).unwrap();
+    }
+    for ix in 0..locals {
+        writeln!(out, "local l{}", ix).unwrap();
+    }
+    for entry in pgm.rodata_entries() {
+        let values: Vec<String> = (0..entry.len()).map(|ix| entry.get(ix).unwrap().to_string()).collect();
+        if entry.width == 8 {
+            writeln!(out, "{}", format!(".data D{}: {}", entry.offset, values.join(", ")).trim_end()).unwrap();
+        } else if entry.data.iter().all(|b| (0x20..0x7f).contains(b)) {
+            // printable text is shown as string:
+            let text = String::from_utf8_lossy(entry.data).replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(out, ".string D{}: \"{}\"", entry.offset, text).unwrap();
+        } else {
+            writeln!(out, "{}", format!(".bytes D{}: {}", entry.offset, values.join(", ")).trim_end()).unwrap();
+        }
+    }
+    for &(pos, info, oparg) in &decoded {
+        if labels.contains(&pos) {
+            writeln!(out, "L{}:", pos).unwrap();
+        }
+        let name = info.mnemonic;
+        let line = match info.oparg {
+            Oparg::U8 | Oparg::Syscall => format!("{} {}", name, oparg[0]),
+            Oparg::I8 => format!("{} {}", name, oparg[0] as i8),
+            Oparg::I16 => format!("{} {}", name, i16::from_be_bytes([oparg[0], oparg[1]])),
+            Oparg::I32 => format!("{} {}", name, i32::from_be_bytes([oparg[0], oparg[1], oparg[2], oparg[3]])),
+            Oparg::Const => match pgm.constant(oparg[0]) {
+                Some(v) => format!("{} {}", name, v),
+                None => format!("# {} with invalid constant {}", name, oparg[0]),
+            },
+            Oparg::RoData => {
+                let offset = u16::from_be_bytes([oparg[0], oparg[1]]) as usize;
+                if pgm.rodata_entries().any(|e| e.offset == offset) {
+                    format!("{} D{}", name, offset)
+                } else {
+                    format!("# {} with invalid data {}", name, offset)
+                }
+            },
+            Oparg::Global => format!("{} g{}", name, oparg[0]),
+            Oparg::Local => format!("{} l{}", name, oparg[0]),
+            Oparg::Jump => {
+                let d = dest(pos, oparg);
+                if d >= 0 && labels.contains(&(d as usize)) {
+                    format!("{} L{}", name, d)
+                } else {
+                    format!("# {} to invalid destination {}", name, d)
+                }
+            },
+            Oparg::None => String::from(name),
+        };
+        writeln!(out, "    {:<19} # {}", line, pos).unwrap();
+    }
+    if pos < text.len() {
+        let bytes: Vec<String> = text[pos..].iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(out, "# {}: undecodable bytes: {}", pos, bytes.join(" ")).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::{self, syscall_names, Ran};
+    use crate::vm::{Overflow, RuntimeError, Status};
+
+    /// Runs a program and returns what it output (or printed), and how it ended.
+    fn run(pgm: &Pgm, overflow: Overflow) -> (Vec<i64>, Vec<i64>, Result<Status, RuntimeError>) {
+        let Ran { out, printed, result, .. } = fixture::run(pgm, 100, |vm| vm.overflow = overflow);
+        (out, printed, result)
+    }
+
+    /// Assembles every example program that is valid, with and without optimizing.
+    fn examples() -> Vec<(String, Pgm<'static>, Pgm<'static>)> {
+        let mut examples = vec![];
+        for (name, source) in fixture::examples() {
+            let Ok((plain, _)) = assemble_with_warnings(&name, &source, &syscall_names(), false) else {
+                continue;
+            };
+            let (optimized, _) = assemble_with_warnings(&name, &source, &syscall_names(), true).unwrap();
+            examples.push((name, plain, optimized));
+        }
+        assert!(examples.len() > 10);
+        examples
+    }
+
+    #[test]
+    fn disassembly_assembles_to_the_same_program() {
+        for (name, plain, optimized) in examples() {
+            for pgm in [plain, optimized] {
+                let source = disassemble(&pgm);
+                let again = assemble(&name, &source, &syscall_names()).unwrap();
+                assert_eq!(again.text, pgm.text, "{}", name);
+                assert_eq!(again.vars, pgm.vars, "{}", name);
+                assert_eq!(again.memory, pgm.memory, "{}", name);
+                assert_eq!(again.consts, pgm.consts, "{}", name);
+                assert_eq!(again.rodata, pgm.rodata, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn optimized_examples_behave_the_same() {
+        for (name, plain, optimized) in examples() {
+            assert!(optimized.text.len() <= plain.text.len(), "{} got longer", name);
+            for overflow in [Overflow::Wrapping, Overflow::Saturating, Overflow::Trapping] {
+                assert_eq!(run(&optimized, overflow), run(&plain, overflow), "{} ({:?})", name, overflow);
+            }
+        }
+    }
+
+    #[test]
+    fn optimize_folds_and_removes() {
+        let source = "
+            push_u8 2
+            push_u8 3
+            mul
+            nop
+            push_u8 7
+            pop
+            goto skip
+            push_u8 99
+            out
+        skip:
+            out
+            fin
+        ";
+        let (pgm, _) = assemble_with_warnings("fold", source, &[], true).unwrap();
+        assert_eq!(&pgm.text[..], &[op::PUSH_U8, 6, op::OUT, op::FIN]);
+    }
+
+    #[test]
+    fn optimize_drops_unused_constants() {
+        let source = "
+            push 0x10000000000
+            push_u8 2
+            mul
+            push 0x20000000000
+            out
+            out
+            fin
+        ";
+        let (pgm, _) = assemble_with_warnings("consts", source, &[], true).unwrap();
+        assert_eq!(pgm.const_count(), 1);
+        assert_eq!(pgm.constant(0), Some(0x20000000000));
+        let again = assemble("consts", &disassemble(&pgm), &[]).unwrap();
+        assert_eq!(again.text, pgm.text);
+        assert_eq!(again.consts, pgm.consts);
+    }
+
+    #[test]
+    fn optimize_keeps_what_can_fail() {
+        // overflow and division by zero must still happen when running:
+        let source = "
+            push 0x7fffffffffffffff
+            push_u8 1
+            add
+            push_u8 1
+            push_u8 0
+            div
+            fin
+        ";
+        let (pgm, _) = assemble_with_warnings("fail", source, &[], true).unwrap();
+        assert_eq!(pgm.text.len(), 11);
+        assert_eq!(run(&pgm, Overflow::Trapping).2, Err(RuntimeError::ArithmeticOverflow));
+        assert_eq!(run(&pgm, Overflow::Wrapping).2, Err(RuntimeError::DivisionByZero));
+        // `dup` and `load_l` can fail, too, even if their value is popped right away:
+        let (pgm, _) = assemble_with_warnings("dup", "dup\npop\npush_u8 1\nout\nfin", &[], true).unwrap();
+        assert_eq!(run(&pgm, Overflow::Wrapping).2, Err(RuntimeError::StackUnderflow));
+        let source = "local x\nload_l x\npop\npush_u8 1\nout\nfin";
+        let (pgm, _) = assemble_with_warnings("local", source, &[], true).unwrap();
+        assert_eq!(run(&pgm, Overflow::Wrapping).2, Err(RuntimeError::StackOverflow));
+    }
+
+    #[test]
+    fn optimize_threads_jumps_only_within_reach() {
+        // `load`/`store` pairs are not removed; each block is 20000 bytes long:
+        let filler = "load x\nstore x\n".repeat(5000);
+        let source = format!("
+            goto start
+        end:
+            fin
+        start:
+            {filler}
+            load x
+            ifeq far
+        near:
+            goto end
+        far:
+            {filler}
+            goto near
+        ");
+        let (plain, _) = assemble_with_warnings("reach", &source, &[], false).unwrap();
+        let (optimized, _) = assemble_with_warnings("reach", &source, &[], true).unwrap();
+        // the last `goto` cannot jump to `end` directly:
+        assert_eq!(optimized.text, plain.text);
+    }
+
+    /// Assembles a program; gives line, columns, and text of each warning.
+    fn warnings(source: &str) -> Vec<(usize, usize, usize, String)> {
+        let (_, report) = assemble_with_warnings("warn", source, &[], false).unwrap();
+        report.warnings.iter().map(|w| (w.span.line, w.span.start, w.span.end, w.warning.to_string())).collect()
+    }
+
+    #[test]
+    fn warns_about_labels_variables_and_unreachable_code() {
+        let source = "\
+start:
+    var unused
+    var stored
+    store stored
+    load loaded
+    push_u8 0
+    call f
+    goto end
+    push_u8 1
+    out
+unused_label:
+    out
+end:
+    fin
+f:
+    local n
+    local x
+    load_l n
+    pop
+    ret
+";
+        assert_eq!(warnings(source), vec![
+            (2, 9, 15, String::from("variable `unused` is never used")),
+            (3, 9, 15, String::from("variable `stored` is stored, but never loaded")),
+            (5, 10, 16, String::from("variable `loaded` is loaded, but never stored")),
+            (9, 5, 14, String::from("unreachable code")),
+            (11, 1, 13, String::from("label `unused_label` is never used")),
+            (17, 11, 12, String::from("local variable `x` is never used")),
+        ]);
+        // a label makes the code behind it reachable, even if it is never used:
+        assert_eq!(warnings("goto end
+here:
+fin
+end:
+fin"), vec![
+            (2, 1, 5, String::from("label `here` is never used")),
+        ]);
+        assert_eq!(warnings("push_u8 1
+out
+fin
+"), vec![]);
+    }
+
+    #[test]
+    fn warnings_are_shown_with_their_source() {
+        let (_, report) = assemble_with_warnings("warn", "fin
+out
+", &[], false).unwrap();
+        assert_eq!(report.to_string(), AsmWarningReport {
+            name: String::from("warn"),
+            warnings: vec![AsmWarningIssue {
+                span: Span { line: 2, start: 1, end: 4 },
+                warning: AsmWarning::UnreachableCode,
+                snippet: String::from("out"),
+            }],
+        }.to_string());
+        assert!(report.to_string().starts_with("program 'warn' has 1 warning:"));
+    }
 }
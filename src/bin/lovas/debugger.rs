@@ -0,0 +1,380 @@
+//! A simple debugger for lovem programs, used by `lovas debug`.
+//!
+//! Commands are read line by line, so a debugging session can also be scripted, e.g.:
+//! `printf "break pow\ncontinue\nprint locals\n" | lovas debug pgm/call.lva`
+use std::io::{self, BufRead, Write};
+use anyhow::Result;
+use lovem::{op, Pgm, VM};
+use lovem::vm::{Status, Stop, Watch};
+
+/// Text shown for the `help` command.
+const HELP: &str = "\
+Commands:
+  step, s              execute a single instruction
+  next, n              execute a single instruction, stepping over calls
+  finish, f            run until the current function returns
+  continue, c          run until a breakpoint or watchpoint is hit, or the program ends
+  break, b <where>     set a breakpoint at a label, a source line, or *pos (bytecode position)
+  delete, d <where>    remove a breakpoint
+  watch, w <var>       stop whenever a variable is written
+  print, p <what>      print `stack`, `globals`, `locals`, or a variable by name
+  backtrace, bt        show the active function calls
+  run, r               restart the program
+  help, h              show this help
+  quit, q              leave the debugger";
+
+/// State of a debugging session.
+struct Debugger<'d, 'a, W> {
+    /// The VM the program runs in.
+    vm: &'d mut VM<'a>,
+    /// The program that is debugged.
+    pgm: &'d Pgm<'d>,
+    /// Lines of the program's source, if it is available.
+    source: Vec<&'d str>,
+    /// Where messages go to.
+    out: W,
+}
+
+impl<W: Write> Debugger<'_, '_, W> {
+    /// Describes a position in bytecode, as source location if possible.
+    fn location(&self, pc: usize) -> String {
+        crate::location(self.pgm, pc)
+    }
+
+    /// Shows where execution currently is, with the source line, if we have it.
+    fn show_position(&mut self) -> io::Result<()> {
+        let pc = self.vm.pc;
+        let line = self.pgm.debug.as_ref().and_then(|d| d.line(pc));
+        match line.and_then(|l| self.source.get(l - 1).map(|text| (l, text))) {
+            Some((l, text)) => writeln!(self.out, "{}, pc={}\n{:>5}  {}", self.location(pc), pc, l, text.trim()),
+            None => {
+                let mnemonic = self.pgm.text.get(pc).and_then(|&o| op::info(o)).map_or("?", |i| i.mnemonic);
+                writeln!(self.out, "{}, pc={} ({})", self.location(pc), pc, mnemonic)
+            },
+        }
+    }
+
+    /// Tells the user what happened after execution stopped.
+    fn show_status(&mut self, status: &Status) -> io::Result<()> {
+        match status {
+            Status::Running => self.show_position(),
+            Status::Stopped(Stop::Breakpoint(_)) => {
+                write!(self.out, "Breakpoint at ")?;
+                self.show_position()
+            },
+            Status::Stopped(Stop::Watchpoint(watch)) => {
+                let (name, value) = self.watched(*watch);
+                writeln!(self.out, "Watchpoint: {} = {}", name, value.map_or(String::from("?"), |v| v.to_string()))?;
+                self.show_position()
+            },
+            Status::Finished => writeln!(self.out, "Program terminated, op_cnt={}, stack={:?}", self.vm.op_cnt, self.vm.stack_values()),
+            Status::Faulted(report) => writeln!(self.out, "Runtime error at {}: {}", self.location(report.pos), report),
+        }
+    }
+
+    /// Is the program in a state where it can continue executing?
+    fn is_running(&self) -> bool {
+        matches!(self.vm.status, Status::Running | Status::Stopped(_))
+    }
+
+    /// Executes exactly one instruction, even if it is a breakpoint.
+    fn single_step(&mut self) -> Status {
+        let pc = self.vm.pc;
+        match self.vm.step(self.pgm) {
+            // we did not move, because we were not stopped at the breakpoint yet:
+            Status::Stopped(Stop::Breakpoint(pos)) if pos == pc => self.vm.step(self.pgm),
+            status => status,
+        }
+    }
+
+    /// Executes at least one instruction, then continues until `done` says so, or execution stops.
+    fn run_until(&mut self, done: impl Fn(&VM) -> bool) -> Status {
+        let mut status = self.single_step();
+        while status == Status::Running && !done(self.vm) {
+            status = self.vm.step(self.pgm);
+        }
+        status
+    }
+
+    /// Names of the global variables, by index.
+    fn global_names(&self) -> Vec<String> {
+        let names = self.pgm.debug.as_ref().map_or(&[][..], |d| &d.vars[..]);
+        (0..self.pgm.vars as usize)
+            .map(|ix| names.get(ix).cloned().unwrap_or_else(|| format!("g{}", ix)))
+            .collect()
+    }
+
+    /// Names of the local variables that are valid at the current position.
+    fn local_names(&self) -> Vec<String> {
+        self.pgm.debug.as_ref().map_or(vec![], |d| d.local_names(self.vm.pc).to_vec())
+    }
+
+    /// Finds a variable by name; locals hide globals.
+    ///
+    /// Without names from the debug information, `l0`, `g0`, ... can be used.
+    fn variable(&self, name: &str) -> Option<Watch> {
+        let indexed = |prefix: &str| name.strip_prefix(prefix).and_then(|ix| ix.parse::<u8>().ok());
+        if let Some(ix) = self.local_names().iter().position(|n| n == name) {
+            Some(Watch::Local(ix as u8))
+        } else if let Some(ix) = self.global_names().iter().position(|n| n == name) {
+            Some(Watch::Global(ix as u8))
+        } else if let Some(ix) = indexed("l") {
+            Some(Watch::Local(ix))
+        } else {
+            indexed("g").filter(|&ix| ix < self.pgm.vars).map(Watch::Global)
+        }
+    }
+
+    /// Gets name and current value of a variable.
+    fn watched(&self, var: Watch) -> (String, Option<i64>) {
+        match var {
+            Watch::Global(ix) => {
+                let name = self.global_names().get(ix as usize).cloned().unwrap_or_else(|| format!("g{}", ix));
+                (name, self.vm.stack_values().get(ix as usize).copied())
+            },
+            Watch::Local(ix) => {
+                let name = self.local_names().get(ix as usize).cloned().unwrap_or_else(|| format!("l{}", ix));
+                (name, self.vm.stack_values().get(self.vm.fb + ix as usize).copied())
+            },
+        }
+    }
+
+    /// Finds the bytecode position for a breakpoint: a label, a source line, or `*pos`.
+    fn breakpoint_position(&self, arg: &str) -> Option<usize> {
+        if let Some(pos) = arg.strip_prefix('*') {
+            return pos.parse().ok();
+        }
+        let debug = self.pgm.debug.as_ref()?;
+        match arg.parse::<usize>() {
+            Ok(line) => debug.position(line),
+            Err(_) => debug.label(arg),
+        }
+    }
+
+    /// Shows the value of a variable.
+    fn show_variable(&mut self, var: Watch) -> io::Result<()> {
+        let (name, value) = self.watched(var);
+        match value {
+            Some(v) => writeln!(self.out, "{} = {}", name, v),
+            None => writeln!(self.out, "{} is not set", name),
+        }
+    }
+
+    fn print(&mut self, what: &str) -> io::Result<()> {
+        match what {
+            "stack" => writeln!(self.out, "{:?} (fb={}, sp={})", self.vm.stack_values(), self.vm.fb, self.vm.sp),
+            "globals" => (0..self.pgm.vars).try_for_each(|ix| self.show_variable(Watch::Global(ix))),
+            "locals" => (0..self.local_names().len()).try_for_each(|ix| self.show_variable(Watch::Local(ix as u8))),
+            name => match self.variable(name) {
+                Some(var) => self.show_variable(var),
+                None => writeln!(self.out, "Unknown variable `{}`.", name),
+            },
+        }
+    }
+
+    fn backtrace(&mut self) -> io::Result<()> {
+        writeln!(self.out, "#0  {}", self.location(self.vm.pc))?;
+        for (ix, frame) in self.vm.frames(self.pgm).enumerate() {
+            writeln!(self.out, "#{}  {}", ix + 1, self.location(crate::call_site(frame.ret)))?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single command; returns `false` if the session should end.
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let (cmd, arg) = match line.split_once(' ') {
+            Some((cmd, arg)) => (cmd, Some(arg.trim())),
+            None => (line, None),
+        };
+        let executes = matches!(cmd, "step" | "s" | "next" | "n" | "finish" | "f" | "continue" | "c");
+        if executes && !self.is_running() {
+            writeln!(self.out, "The program is not running, use `run` to restart it.")?;
+            return Ok(true);
+        }
+        match (cmd, arg) {
+            ("step" | "s", None) => {
+                let status = self.single_step();
+                self.show_status(&status)?;
+            },
+            ("next" | "n", None) => {
+                let status = if self.pgm.text.get(self.vm.pc) == Some(&op::CALL) {
+                    // run until the call returns to this frame:
+                    let (ret, fb) = (self.vm.pc + 3, self.vm.fb);
+                    self.run_until(|vm| vm.pc == ret && vm.fb == fb)
+                } else {
+                    self.single_step()
+                };
+                self.show_status(&status)?;
+            },
+            ("finish" | "f", None) => {
+                if self.vm.frames(self.pgm).next().is_none() {
+                    writeln!(self.out, "Not inside a function.")?;
+                } else {
+                    // returning restores the caller's frame base, which is lower:
+                    let fb = self.vm.fb;
+                    let status = self.run_until(|vm| vm.fb < fb);
+                    self.show_status(&status)?;
+                }
+            },
+            ("continue" | "c", None) => {
+                let status = self.run_until(|_| false);
+                self.show_status(&status)?;
+            },
+            ("break" | "b", Some(arg)) => match self.breakpoint_position(arg) {
+                Some(pos) => match self.vm.add_breakpoint(pos) {
+                    Ok(()) => writeln!(self.out, "Breakpoint set at {}, pc={}", self.location(pos), pos)?,
+                    Err(e) => writeln!(self.out, "Cannot set breakpoint: {}", e)?,
+                },
+                None => writeln!(self.out, "No code found for `{}`.", arg)?,
+            },
+            ("delete" | "d", Some(arg)) => match self.breakpoint_position(arg) {
+                Some(pos) if self.vm.remove_breakpoint(pos) => writeln!(self.out, "Breakpoint at pc={} removed.", pos)?,
+                _ => writeln!(self.out, "No breakpoint at `{}`.", arg)?,
+            },
+            ("watch" | "w", Some(arg)) => match self.variable(arg) {
+                Some(var) => match self.vm.add_watchpoint(var) {
+                    Ok(()) => writeln!(self.out, "Watching {}.", arg)?,
+                    Err(e) => writeln!(self.out, "Cannot set watchpoint: {}", e)?,
+                },
+                None => writeln!(self.out, "Unknown variable `{}`.", arg)?,
+            },
+            ("print" | "p", Some(arg)) => self.print(arg)?,
+            ("backtrace" | "bt", None) => self.backtrace()?,
+            ("run" | "r", None) => {
+                match self.vm.reset(self.pgm) {
+                    Ok(()) => self.show_position()?,
+                    Err(report) => writeln!(self.out, "Cannot start program: {}", report)?,
+                }
+            },
+            ("help" | "h", None) => writeln!(self.out, "{}", HELP)?,
+            ("quit" | "q", None) => return Ok(false),
+            _ => writeln!(self.out, "Invalid command `{}`, try `help`.", line)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Runs a debugging session for a program, reading commands from `input` until it ends.
+///
+/// `source` is the program's assembler source, used to show the current line. Everything the
+/// debugger tells goes to `out`; a prompt is only shown, if the session is `interactive`.
+pub fn debug(vm: &mut VM, pgm: &Pgm, source: Option<&str>, input: impl BufRead, out: impl Write, interactive: bool) -> Result<()> {
+    let mut debugger = Debugger {
+        vm,
+        pgm,
+        source: source.map_or(vec![], |s| s.lines().collect()),
+        out,
+    };
+    writeln!(debugger.out, "Debugging program '{}'. Type `help` for a list of commands.", pgm.name)?;
+    if let Err(report) = debugger.vm.reset(pgm) {
+        writeln!(debugger.out, "Cannot start program: {}", report)?;
+    } else {
+        debugger.show_position()?;
+    }
+    let mut lines = input.lines();
+    loop {
+        if interactive {
+            write!(debugger.out, "(lovdb) ")?;
+            debugger.out.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let line = line.trim();
+        if !line.is_empty() && !debugger.command(line)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lovem::asm;
+
+    /// Debugs `pgm/call.lva` with a script of commands; returns what the debugger wrote.
+    fn session(script: &str) -> String {
+        let source = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/pgm/call.lva")).unwrap();
+        let pgm = asm::assemble("call.lva", &source, &[]).unwrap();
+        let mut stack = [0; 32];
+        let mut vm = VM::new(&mut stack);
+        let mut out = vec![];
+        debug(&mut vm, &pgm, Some(&source), script.as_bytes(), &mut out, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let script = "
+            break 17
+            continue
+            backtrace
+            print locals
+            next
+            watch a
+            continue
+            print a
+            finish
+            print stack
+            continue
+            step
+        ";
+        // line 17 is the label `pow`, the breakpoint goes to the first line with code after it:
+        let expected = "\
+Debugging program 'call.lva'. Type `help` for a list of commands.
+call.lva:2 (in start), pc=0
+    2  push_u8 5
+Breakpoint set at call.lva:22 (in pow), pc=15
+Breakpoint at call.lva:22 (in pow), pc=15
+   22  load_l b
+#0  call.lva:22 (in pow)
+#1  call.lva:5 (in start)
+a = 5
+b = 3
+call.lva:23 (in pow), pc=17
+   23  iflt fail
+Watching a.
+Watchpoint: a = 125
+call.lva:42 (in pow), pc=47
+   42  ret
+a = 125
+call.lva:6 (in start), pc=9
+    6  pop
+[125, 3] (fb=0, sp=2)
+Program terminated, op_cnt=38, stack=[]
+The program is not running, use `run` to restart it.
+";
+        assert_eq!(session(script), expected);
+    }
+
+    #[test]
+    fn breakpoints_are_removed_and_restarted() {
+        let script = "
+            break pow
+            delete 17
+            delete pow
+            break 100
+            print c
+            continue
+            run
+            jump
+        ";
+        let expected = "\
+Debugging program 'call.lva'. Type `help` for a list of commands.
+call.lva:2 (in start), pc=0
+    2  push_u8 5
+Breakpoint set at call.lva:22 (in pow), pc=15
+Breakpoint at pc=15 removed.
+No breakpoint at `pow`.
+No code found for `100`.
+Unknown variable `c`.
+Program terminated, op_cnt=38, stack=[]
+call.lva:2 (in start), pc=0
+    2  push_u8 5
+Invalid command `jump`, try `help`.
+";
+        assert_eq!(session(script), expected);
+    }
+}
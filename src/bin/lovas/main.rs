@@ -0,0 +1,396 @@
+//! An experimental assembler for lovem
+use std::io::IsTerminal;
+use std::time::Instant;
+use clap::{Parser, Subcommand};
+use anyhow::{Context, Error, Result};
+use lovem::{asm, graph, pgm, stack, verify, Pgm, VM};
+use lovem::op::Oparg;
+use lovem::stack::StackDepth;
+use lovem::vm::Overflow;
+use lovem::syscall::{NativeFn, Syscall, SyscallTable};
+use lovem::observer::{Observer, StdoutObserver};
+use lovem::profile::Profiler;
+use lovem::coverage::{Coverage, CoverageReport};
+
+mod debugger;
+
+// You can find an introduction to clap here:
+// https://rust-cli.github.io/book/index.html
+
+/// Struct used to declare the command line tool behaviour using clap.
+///
+/// This defines the arguments and options the tool provides. It is also used to
+/// generate the instructions you get when calling it with `--help`.
+#[derive(Parser, Debug)]
+#[clap(name = "lovas",
+long_about = "An experimental assembler for lovem, the Low Overhead Virtual Embedded Machine.",
+args_conflicts_with_subcommands = true,
+subcommand_negates_reqs = true,
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(parse(from_os_str), required = true, help = "Path to assembler source file, or to an object file to run.")]
+    source: Option<std::path::PathBuf>,
+
+    #[clap(short, long, parse(from_os_str), help = "Write the assembled program to an object file.")]
+    output: Option<std::path::PathBuf>,
+
+    #[clap(short, long, help = "Run the assembled program in lovem.")]
+    run: bool,
+
+    #[clap(long, help = "Enable tracing log when running lovem.")]
+    trace: bool,
+
+    #[clap(long, help = "Count executed instructions and print the hottest lines and functions after running.")]
+    profile: bool,
+
+    #[clap(long, parse(from_os_str), help = "Record code coverage and merge it into an lcov tracefile.")]
+    coverage: Option<std::path::PathBuf>,
+
+    #[clap(short = 'W', long, global = true, default_value = "warn", possible_values = ["allow", "warn", "error"],
+    help = "What to do with assembler warnings: ignore them, print them, or fail because of them.")]
+    warnings: String,
+
+    #[clap(short = 'O', long, global = true, help = "Optimize the program: fold constants, remove useless instructions and unreachable code, shorten jumps.")]
+    optimize: bool,
+
+    #[clap(long, help = "Output the program to stdout.")]
+    print: bool,
+
+    #[clap(long, help = "Setting the stack size for lovem when running the program [default: the program's stack bound if it is known, else 100].")]
+    stack_size: Option<usize>,
+
+    #[clap(long, help = "Analyse the maximal stack depth of the program, report it, and store it in the program.")]
+    stack_depth: bool,
+
+    #[clap(long, default_value_t = 1000000, help = "Limit max number of instructions allowed for execution. 0 for unlimited.")]
+    instruction_limit: usize,
+
+    #[clap(long, default_value = "wrapping", possible_values = ["wrapping", "saturating", "trapping"],
+    help = "Behaviour of arithmetic instructions on integer overflow.")]
+    overflow: String,
+}
+
+/// Additional tools lovas provides as subcommands.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Disassemble a program and print it as assembler source.
+    Disasm {
+        #[clap(parse(from_os_str), help = "Path to object file, or to assembler source file.")]
+        source: std::path::PathBuf,
+    },
+    /// Run a program in an interactive debugger, reading commands from stdin.
+    Debug {
+        #[clap(parse(from_os_str), help = "Path to assembler source file, or to an object file.")]
+        source: std::path::PathBuf,
+    },
+    /// Show the source files from an lcov tracefile, annotated with their coverage.
+    Coverage {
+        #[clap(parse(from_os_str), help = "Path to lcov tracefile written with `--coverage`.")]
+        tracefile: std::path::PathBuf,
+    },
+    /// Print the control flow graph of every function, or the call graph, in DOT format.
+    Graph {
+        #[clap(parse(from_os_str), help = "Path to assembler source file, or to an object file.")]
+        source: std::path::PathBuf,
+        #[clap(long, help = "Print the call graph instead of the control flow graphs.")]
+        calls: bool,
+    },
+}
+
+/// Native functions lovas provides to programs: name, id, and number of values popped and pushed.
+const SYSCALLS: [(&str, u8, usize, usize); 3] = [("print", 0, 1, 0), ("max", 1, 2, 1), ("min", 2, 2, 1)];
+
+/// Looks up a native function in `SYSCALLS` by name, giving its id, pops, and pushes.
+fn syscall(name: &str) -> (usize, usize, usize) {
+    let &(_, id, pops, pushes) = SYSCALLS.iter().find(|s| s.0 == name).expect("native function is in SYSCALLS");
+    (id as usize, pops, pushes)
+}
+
+/// Creates a lovem VM for a program, configured by the command line, and hands it to `f`.
+fn with_vm<R>(pgm: &Pgm, args: &Cli, observer: &mut dyn Observer, f: impl FnOnce(&mut VM) -> R) -> R {
+    // Native functions lovas provides, with ids and stack effects from `SYSCALLS`:
+    let (print_id, pops, pushes) = syscall("print");
+    let mut print = NativeFn::new(pops, pushes, |args, _| {
+        println!("Print: {}", args[0]);
+        Ok(())
+    });
+    let (max_id, pops, pushes) = syscall("max");
+    let mut max = NativeFn::new(pops, pushes, |args, results| {
+        results[0] = std::cmp::max(args[0], args[1]);
+        Ok(())
+    });
+    let (min_id, pops, pushes) = syscall("min");
+    let mut min = NativeFn::new(pops, pushes, |args, results| {
+        results[0] = std::cmp::min(args[0], args[1]);
+        Ok(())
+    });
+    let mut syscalls: [Option<&mut dyn Syscall>; SYSCALLS.len()] = Default::default();
+    syscalls[print_id] = Some(&mut print);
+    syscalls[max_id] = Some(&mut max);
+    syscalls[min_id] = Some(&mut min);
+    // Create our VM instance:
+    let stack_size = args.stack_size.or(pgm.stack.map(|s| s as usize)).unwrap_or(100);
+    let mut stack = vec![0; stack_size];
+    let mut memory = vec![0; pgm.memory as usize];
+    let mut vm = VM::new(&mut stack);
+    vm.memory = &mut memory;
+    vm.observer = Some(observer);
+    vm.syscalls = SyscallTable::new(&mut syscalls);
+    vm.trace = args.trace;
+    vm.instruction_limit = args.instruction_limit;
+    vm.overflow = match args.overflow.as_str() {
+        "saturating" => Overflow::Saturating,
+        "trapping" => Overflow::Trapping,
+        _ => Overflow::Wrapping,
+    };
+    f(&mut vm)
+}
+
+/// Executes a program in a freshly created lovem VM, printing output to stdout.
+///
+/// With `--profile`, a report of the hottest lines and functions follows; the source is
+/// used to show the lines. With `--coverage`, the executed lines are added to a tracefile.
+fn run(pgm: &Pgm, args: &Cli, source: Option<&str>) -> Result<()> {
+    // Observers are chained: coverage, then profiler, then stdout; each one passes on the output.
+    // They only count while tracing, so they cost nothing unless they are asked for.
+    let mut stdout = StdoutObserver;
+    let mut profiler = Profiler::new(Some(&mut stdout));
+    profiler.forward.forward_trace = args.trace;
+    let mut coverage = Coverage::new(Some(&mut profiler));
+    coverage.forward.forward_trace = true;
+    let result = with_vm(pgm, args, &mut coverage, |vm| {
+        // profiler and coverage use the events reported while tracing:
+        vm.trace |= args.profile || args.coverage.is_some();
+        execute(vm, pgm)
+    });
+    if let Some(path) = &args.coverage {
+        let mut report = match std::fs::read_to_string(path) {
+            Ok(data) => CoverageReport::from_lcov(&data)
+                .with_context(|| format!("could not read tracefile `{}`", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => CoverageReport::default(),
+            Err(e) => return Err(Error::from(e).context(format!("could not read tracefile `{}`", path.display()))),
+        };
+        report.add(&coverage, pgm).context("coverage needs a program with debug information")?;
+        std::fs::write(path, report.to_lcov())
+            .with_context(|| format!("could not write file `{}`", path.display()))?;
+    }
+    if args.profile {
+        // the program might have stopped with an error, inside of functions:
+        profiler.finish();
+        eprint!("{}", profiler.report(pgm, source, 10));
+    }
+    result
+}
+
+/// Prints the source files recorded in an lcov tracefile, annotated with their coverage.
+fn show_coverage(tracefile: &std::path::Path) -> Result<()> {
+    let data = std::fs::read_to_string(tracefile)
+        .with_context(|| format!("could not read file `{}`", tracefile.display()))?;
+    let report = CoverageReport::from_lcov(&data)
+        .with_context(|| format!("could not read tracefile `{}`", tracefile.display()))?;
+    for (name, file) in &report.files {
+        let source = std::fs::read_to_string(name)
+            .with_context(|| format!("could not read source file `{}`", name))?;
+        println!("{}:", name);
+        print!("{}", file.annotate(&source));
+    }
+    Ok(())
+}
+
+/// Runs a program in a VM and reports the outcome.
+fn execute(vm: &mut VM, pgm: &Pgm) -> Result<()> {
+    let start = Instant::now();
+    let outcome = vm.run(pgm);
+    let duration = start.elapsed();
+    match outcome {
+        Ok(_) => {
+            // Execution successful, program terminated:
+            eprintln!("Terminated.\nRuntime={:?}\nop_cnt={}, pc={}, stack-depth={}, watermark={}",
+                      duration,
+                      vm.op_cnt, vm.pc, vm.sp, vm.watermark
+            );
+            Ok(())
+        },
+        Err(report) => {
+            // Runtime error. Error will be printed on return of main.
+            // Tell where it happened in the source, if we have debug information:
+            eprintln!("Runtime error at {}!\nRuntime={:?}\nop_cnt={}, pc={}, stack-depth={}, watermark={}",
+                      location(pgm, report.pos), duration, vm.op_cnt, vm.pc, vm.sp, vm.watermark);
+            // Show the chain of calls that lead to the error:
+            for &ret in report.backtrace() {
+                eprintln!("  called from {}", location(pgm, call_site(ret as usize)));
+            }
+            Err(Error::from(report))
+        }
+    }
+}
+
+/// Gets the assembler source from a file's content, or `None` for an object file.
+fn source_text(data: &[u8]) -> Option<&str> {
+    if data.starts_with(&pgm::MAGIC) {
+        None
+    } else {
+        std::str::from_utf8(data).ok()
+    }
+}
+
+/// Describes a position in bytecode, as source location if the program has debug information.
+fn location(pgm: &Pgm, pc: usize) -> String {
+    pgm.debug.as_ref()
+        .and_then(|d| d.location(pc))
+        .unwrap_or_else(|| format!("pc={}", pc))
+}
+
+/// Finds the `CALL` instruction for a return address; it is right before the address.
+fn call_site(ret: usize) -> usize {
+    ret.saturating_sub(1 + Oparg::Jump.size())
+}
+
+/// Reads a complete file, returning its name for messages and its content.
+fn read_file(path: &std::path::Path) -> Result<(String, Vec<u8>)> {
+    // Store the path to the program in a usable place:
+    let name = path.display().to_string();
+    let data = std::fs::read(path)
+        .with_context(
+            || format!("could not read file `{}`", &name)
+        )?;
+    Ok((name, data))
+}
+
+/// Gets a program from a file's content, which is either an object file or assembler source.
+///
+/// Assembler warnings are handled as `-W` says; with `-O`, the program is optimized.
+fn load_program<'a>(name: &str, data: &'a [u8], args: &Cli) -> Result<Pgm<'a>> {
+    if data.starts_with(&pgm::MAGIC) {
+        // this is an object file with an assembled program:
+        Pgm::from_bytes(data)
+            .with_context(|| format!("could not load object file `{}`", name))
+    } else {
+        // this should be assembler source, so run the assembler:
+        let content = std::str::from_utf8(data)
+            .with_context(|| format!("source file `{}` is not valid UTF-8", name))?;
+        // Convert an error report, so that `anyhow` can do its magic
+        // and display some helpful error message:
+        let names: Vec<(&str, u8)> = SYSCALLS.iter().map(|&(name, id, _, _)| (name, id)).collect();
+        let (pgm, warnings) = asm::assemble_with_warnings(name, content, &names, args.optimize)?;
+        if !warnings.is_empty() {
+            match args.warnings.as_str() {
+                "error" => return Err(Error::from(warnings)),
+                "warn" => eprintln!("{}", warnings),
+                _ => {},
+            }
+        }
+        Ok(pgm)
+    }
+}
+
+fn main() -> Result<()> {
+    // read, validate, and evaluate command line parameters:
+    let args = Cli::parse();
+    match &args.command {
+        Some(Command::Disasm { source }) => {
+            let (name, data) = read_file(source)?;
+            let pgm = load_program(&name, &data, &args)?;
+            print!("{}", asm::disassemble(&pgm));
+            return Ok(());
+        },
+        Some(Command::Debug { source }) => {
+            let (name, data) = read_file(source)?;
+            let pgm = load_program(&name, &data, &args)?;
+            // the debugger executes the program, so verify it like `--run` does:
+            verify::verify(&pgm).with_context(|| format!("verification of program `{}` failed", name))?;
+            // show source lines while debugging, if we have them:
+            let source = source_text(&data);
+            // only show a prompt, if a human is typing:
+            let stdin = std::io::stdin();
+            let interactive = stdin.is_terminal();
+            let mut observer = StdoutObserver;
+            return with_vm(&pgm, &args, &mut observer, |vm| debugger::debug(vm, &pgm, source, stdin.lock(), std::io::stdout(), interactive));
+        },
+        Some(Command::Coverage { tracefile }) => return show_coverage(tracefile),
+        Some(Command::Graph { source, calls }) => {
+            let (name, data) = read_file(source)?;
+            let pgm = load_program(&name, &data, &args)?;
+            if *calls {
+                print!("{}", graph::call_graph_dot(&pgm));
+            } else {
+                let source = source_text(&data);
+                print!("{}", graph::control_flow_dot(&pgm, source));
+            }
+            return Ok(());
+        },
+        None => {},
+    }
+    // clap makes sure we have a source, if there is no subcommand:
+    let (name, data) = read_file(args.source.as_ref().unwrap())?;
+    let mut pgm = load_program(&name, &data, &args)?;
+    // we succeeded and now have a program with bytecode:
+    if args.stack_depth {
+        // lovas was called with `--stack-depth`, find out how much stack the program needs:
+        let arities = |id: u8| SYSCALLS.iter().find(|s| s.1 == id).map(|&(_, _, pops, pushes)| (pops, pushes));
+        match stack::max_depth(&pgm, &arities) {
+            Ok(StackDepth::Bounded(depth)) => {
+                eprintln!("Max stack depth: {}", depth);
+                pgm.stack = Some(depth as u32);
+            },
+            Ok(StackDepth::Unbounded { function }) => {
+                let name = pgm.debug.as_ref()
+                    .and_then(|d| d.symbols.iter().find(|s| s.pos == function))
+                    .map_or_else(|| format!("pc={}", function), |s| s.name.clone());
+                eprintln!("Max stack depth: unbounded, because of recursion in {}", name);
+            },
+            Err(report) => {
+                if let Some(location) = pgm.debug.as_ref().and_then(|d| d.location(report.pos)) {
+                    eprintln!("Stack analysis failed at {}", location);
+                }
+                return Err(Error::from(report));
+            },
+        }
+    }
+    if args.print {
+        println!("{:?}", pgm);
+    }
+    if let Some(output) = &args.output {
+        // lovas was called with `-o`, write the program to an object file:
+        std::fs::write(output, pgm.to_bytes())
+            .with_context(|| format!("could not write file `{}`", output.display()))?;
+    }
+    if args.run {
+        // lovas was called with `--run`, so verify the bytecode before we execute it:
+        verify::verify(&pgm).with_context(|| format!("verification of program `{}` failed", name))?;
+        // create a VM and execute program:
+        let source = source_text(&data);
+        run(&pgm, &args, source)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads a program from assembler source, with the given command line.
+    fn load(args: &[&str], source: &str) -> Result<()> {
+        let args = Cli::parse_from(args);
+        load_program("warn.lva", source.as_bytes(), &args).map(|_| ())
+    }
+
+    #[test]
+    fn warnings_as_errors() {
+        let source = "fin\nout\n";
+        assert!(load(&["lovas", "warn.lva"], source).is_ok());
+        assert!(load(&["lovas", "-W", "allow", "warn.lva"], source).is_ok());
+        let err = load(&["lovas", "-W", "error", "warn.lva"], source).unwrap_err();
+        let report = err.downcast_ref::<asm::AsmWarningReport>().unwrap();
+        assert_eq!(report.warnings[0].warning, asm::AsmWarning::UnreachableCode);
+        assert_eq!(report.warnings[0].span, asm::Span { line: 2, start: 1, end: 4 });
+        // without warnings, there is nothing to fail for:
+        assert!(load(&["lovas", "-W", "error", "warn.lva"], "push_u8 1\nout\nfin\n").is_ok());
+        // errors are still errors:
+        let err = load(&["lovas", "-W", "allow", "warn.lva"], "nope\n").unwrap_err();
+        assert!(err.downcast_ref::<asm::AsmErrorReport>().is_some());
+    }
+}
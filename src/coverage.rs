@@ -0,0 +1,387 @@
+//! Code coverage, to find out which parts of a program were never executed.
+//!
+//! `Coverage` is an `Observer` that records the positions in bytecode the VM executes while
+//! its `trace` is active, and which direction each conditional branch took. A
+//! `CoverageReport` maps that back to source lines using the program's debug information.
+//! Reports from any number of runs can be merged, written as lcov tracefile, read back,
+//! and shown as annotated source.
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Write};
+use crate::op::{self, Flow};
+use crate::Pgm;
+use crate::observer::{Forward, Observer, TraceEvent};
+
+/// An error while creating or reading a coverage report.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoverageError {
+    /// The program has no debug information to map bytecode to source lines.
+    NoDebugInfo,
+    /// A line in an lcov tracefile could not be parsed.
+    InvalidLcov(usize),
+}
+
+impl Display for CoverageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for CoverageError {
+}
+
+/// How often a conditional branch went each way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    /// Number of times the branch jumped.
+    pub taken: u64,
+    /// Number of times execution continued with the next instruction.
+    pub not_taken: u64,
+}
+
+impl BranchCount {
+    /// Adds the counts of another branch.
+    pub fn merge(&mut self, other: &BranchCount) {
+        self.taken += other.taken;
+        self.not_taken += other.not_taken;
+    }
+}
+
+/// Observer that records executed positions and branch directions.
+///
+/// Coverage is usually recorded while tests run, which should still show their output:
+/// it goes on through `forward`, as do trace events when other observers need them.
+pub struct Coverage<'o> {
+    /// Where output (and trace events, if wanted) go after recording.
+    pub forward: Forward<'o>,
+    /// Number of executions, by position in bytecode.
+    pub hits: Vec<u64>,
+    /// Directions taken by conditional branches, by their position in bytecode.
+    pub branches: BTreeMap<usize, BranchCount>,
+    /// Position of a branch instruction that is executing, until we know where it went.
+    branching: Option<usize>,
+}
+
+impl<'o> Coverage<'o> {
+    /// Creates an empty recording, in front of the observer that shows the program's output.
+    pub fn new(inner: Option<&'o mut dyn Observer>) -> Coverage<'o> {
+        Coverage {
+            forward: Forward::new(inner),
+            hits: vec![],
+            branches: BTreeMap::new(),
+            branching: None,
+        }
+    }
+
+    /// Number of times the instruction at a position was executed.
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+}
+
+impl Observer for Coverage<'_> {
+    fn out(&mut self, value: i64, op_cnt: usize) {
+        self.forward.out(value, op_cnt);
+    }
+
+    fn trace(&mut self, event: &TraceEvent) {
+        match *event {
+            TraceEvent::Instruction { pc, opcode, op_cnt, .. } => {
+                // the branch before did not jump, or we would have seen it;
+                // unless a new run starts, then it ended with an error:
+                if let Some(pos) = self.branching.take() {
+                    if op_cnt != 1 {
+                        self.branches.entry(pos).or_default().not_taken += 1;
+                    }
+                }
+                if self.hits.len() <= pc {
+                    self.hits.resize(pc + 1, 0);
+                }
+                self.hits[pc] += 1;
+                if op::info(opcode).map(|i| i.flow) == Some(Flow::Branch) {
+                    self.branching = Some(pc);
+                }
+            },
+            TraceEvent::Jump { .. } => {
+                if let Some(pos) = self.branching.take() {
+                    self.branches.entry(pos).or_default().taken += 1;
+                }
+            },
+            TraceEvent::Call { .. } | TraceEvent::Return { .. } | TraceEvent::Terminated { .. } => {},
+        }
+        self.forward.trace(event);
+    }
+}
+
+/// Coverage of a single source file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileCoverage {
+    /// Number of executions, by source line; only lines that hold instructions are listed.
+    pub lines: BTreeMap<usize, u64>,
+    /// Conditional branches, by source line and position in bytecode.
+    pub branches: BTreeMap<(usize, usize), BranchCount>,
+}
+
+impl FileCoverage {
+    /// Number of lines with instructions, and how many of them were executed.
+    pub fn line_summary(&self) -> (usize, usize) {
+        (self.lines.len(), self.lines.values().filter(|&&n| n > 0).count())
+    }
+
+    /// Number of branch directions, and how many of them were taken.
+    pub fn branch_summary(&self) -> (usize, usize) {
+        let hit = self.branches.values()
+            .map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize)
+            .sum();
+        (self.branches.len() * 2, hit)
+    }
+
+    /// Shows the source with the number of executions in front of every line.
+    ///
+    /// Lines without instructions show `-`, lines never executed `#####`. Every branch is
+    /// followed by how often it jumped and how often it fell through.
+    pub fn annotate(&self, source: &str) -> String {
+        let mut out = String::new();
+        for (ix, text) in source.lines().enumerate() {
+            let line = ix + 1;
+            let count = match self.lines.get(&line) {
+                None => String::from("-"),
+                Some(0) => String::from("#####"),
+                Some(n) => n.to_string(),
+            };
+            writeln!(out, "{:>9}:{:>5}: {}", count, line, text).unwrap();
+            for (&(_, pos), b) in self.branches.range((line, 0)..(line + 1, 0)) {
+                let mark = if b.taken == 0 || b.not_taken == 0 { "!" } else { " " };
+                writeln!(out, "{:>9} {:>5}  branch at pc={}: jumped {}, fell through {}",
+                         mark, "", pos, b.taken, b.not_taken).unwrap();
+            }
+        }
+        let (lines, lines_hit) = self.line_summary();
+        let (branches, branches_hit) = self.branch_summary();
+        writeln!(out, "Lines executed: {}", percentage(lines_hit, lines)).unwrap();
+        writeln!(out, "Branches taken: {}", percentage(branches_hit, branches)).unwrap();
+        out
+    }
+
+    /// Adds the counts of another coverage of the same file.
+    pub fn merge(&mut self, other: &FileCoverage) {
+        for (&line, &n) in &other.lines {
+            *self.lines.entry(line).or_default() += n;
+        }
+        for (&key, b) in &other.branches {
+            self.branches.entry(key).or_default().merge(b);
+        }
+    }
+}
+
+/// Formats `hit` of `total` like `3/4 (75.00%)`.
+fn percentage(hit: usize, total: usize) -> String {
+    if total == 0 {
+        format!("{}/{}", hit, total)
+    } else {
+        format!("{}/{} ({:.2}%)", hit, total, hit as f64 * 100.0 / total as f64)
+    }
+}
+
+/// Coverage of source files, collected over any number of runs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CoverageReport {
+    /// Coverage by name of the source file.
+    pub files: BTreeMap<String, FileCoverage>,
+}
+
+impl CoverageReport {
+    /// Adds what a `Coverage` recorded while running a program.
+    ///
+    /// The program's debug information maps the positions to source lines. All instructions
+    /// of the program are listed, including those never executed.
+    pub fn add(&mut self, coverage: &Coverage, pgm: &Pgm) -> Result<(), CoverageError> {
+        let debug = pgm.debug.as_ref().ok_or(CoverageError::NoDebugInfo)?;
+        let mut file = FileCoverage::default();
+        // decode the program, to find every instruction:
+        let mut pos = 0;
+        while let Some(info) = pgm.text.get(pos).and_then(|&o| op::info(o)) {
+            if let Some(line) = debug.line(pos) {
+                let n = file.lines.entry(line).or_default();
+                *n = (*n).max(coverage.hits(pos));
+                if info.flow == Flow::Branch {
+                    let b = coverage.branches.get(&pos).copied().unwrap_or_default();
+                    file.branches.insert((line, pos), b);
+                }
+            }
+            pos += 1 + info.oparg.size();
+        }
+        self.files.entry(debug.file.clone()).or_default().merge(&file);
+        Ok(())
+    }
+
+    /// Adds the counts of another report.
+    pub fn merge(&mut self, other: &CoverageReport) {
+        for (name, file) in &other.files {
+            self.files.entry(name.clone()).or_default().merge(file);
+        }
+    }
+
+    /// Writes the report in lcov's tracefile format, as used by `genhtml` and most CI tools.
+    ///
+    /// Branches are written with their position in bytecode as block number; branch 0 is the
+    /// jump, branch 1 the fall through.
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (name, file) in &self.files {
+            writeln!(out, "TN:\nSF:{}", name).unwrap();
+            for (&(line, pos), b) in &file.branches {
+                let executed = b.taken > 0 || b.not_taken > 0 || file.lines.get(&line).is_some_and(|&n| n > 0);
+                for (branch, n) in [b.taken, b.not_taken].into_iter().enumerate() {
+                    if executed {
+                        writeln!(out, "BRDA:{},{},{},{}", line, pos, branch, n).unwrap();
+                    } else {
+                        writeln!(out, "BRDA:{},{},{},-", line, pos, branch).unwrap();
+                    }
+                }
+            }
+            let (branches, branches_hit) = file.branch_summary();
+            writeln!(out, "BRF:{}\nBRH:{}", branches, branches_hit).unwrap();
+            for (line, n) in &file.lines {
+                writeln!(out, "DA:{},{}", line, n).unwrap();
+            }
+            let (lines, lines_hit) = file.line_summary();
+            writeln!(out, "LF:{}\nLH:{}\nend_of_record", lines, lines_hit).unwrap();
+        }
+        out
+    }
+
+    /// Reads a report from an lcov tracefile, like one written by `to_lcov`.
+    ///
+    /// Only line and branch records are used; records for functions and summaries are
+    /// skipped, as they can be calculated.
+    pub fn from_lcov(data: &str) -> Result<CoverageReport, CoverageError> {
+        let mut report = CoverageReport::default();
+        let mut current: Option<(String, FileCoverage)> = None;
+        for (ix, line) in data.lines().enumerate() {
+            let invalid = || CoverageError::InvalidLcov(ix + 1);
+            let line = line.trim();
+            let (key, value) = line.split_once(':').unwrap_or((line, ""));
+            let numbers = || value.split(',')
+                .map(|v| if v == "-" { Ok(0) } else { v.parse::<u64>().map_err(|_| invalid()) })
+                .collect::<Result<Vec<u64>, CoverageError>>();
+            match key {
+                "SF" => current = Some((value.to_string(), FileCoverage::default())),
+                "DA" => {
+                    let file = &mut current.as_mut().ok_or_else(invalid)?.1;
+                    // an optional checksum may follow the count:
+                    let v: Vec<&str> = value.split(',').collect();
+                    let (line, n) = match v[..] {
+                        [line, n, ..] => (line.parse().map_err(|_| invalid())?, n.parse::<u64>().map_err(|_| invalid())?),
+                        _ => return Err(invalid()),
+                    };
+                    *file.lines.entry(line).or_default() += n;
+                },
+                "BRDA" => {
+                    let file = &mut current.as_mut().ok_or_else(invalid)?.1;
+                    let (line, block, branch, n) = match numbers()?[..] {
+                        [line, block, branch, n] => (line as usize, block as usize, branch, n),
+                        _ => return Err(invalid()),
+                    };
+                    let b = file.branches.entry((line, block)).or_default();
+                    match branch {
+                        0 => b.taken += n,
+                        1 => b.not_taken += n,
+                        _ => return Err(invalid()),
+                    }
+                },
+                "end_of_record" => {
+                    let (name, file) = current.take().ok_or_else(invalid)?;
+                    report.files.entry(name).or_default().merge(&file);
+                },
+                _ => {},
+            }
+        }
+        match current {
+            None => Ok(report),
+            Some(_) => Err(CoverageError::InvalidLcov(data.lines().count())),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::vm::VM;
+
+    /// Counts down from 3, then checks if the result is zero, which it always is.
+    const SOURCE: &str = "\
+push_u8 3
+loop:
+push_u8 1
+sub
+dup
+ifgt loop
+ifne never
+fin
+never:
+fin
+";
+
+    /// Runs a program with coverage recording, as often as asked.
+    fn record(pgm: &Pgm, runs: usize) -> Coverage<'static> {
+        let mut coverage = Coverage::new(None);
+        let mut stack = [0; 8];
+        let mut vm = VM::new(&mut stack);
+        vm.trace = true;
+        vm.observer = Some(&mut coverage);
+        for _ in 0..runs {
+            vm.run(pgm).unwrap();
+        }
+        coverage
+    }
+
+    /// Creates the report for some runs of a program.
+    fn report(pgm: &Pgm, runs: usize) -> CoverageReport {
+        let mut report = CoverageReport::default();
+        report.add(&record(pgm, runs), pgm).unwrap();
+        report
+    }
+
+    #[test]
+    fn branch_directions() {
+        let pgm = asm::assemble("loop.lva", SOURCE, &[]).unwrap();
+        let coverage = record(&pgm, 1);
+        let branches: Vec<(usize, BranchCount)> = coverage.branches.iter().map(|(&p, &b)| (p, b)).collect();
+        assert_eq!(branches, vec![
+            (6, BranchCount { taken: 2, not_taken: 1 }),
+            (9, BranchCount { taken: 0, not_taken: 1 }),
+        ]);
+        let file = &report(&pgm, 1).files["loop.lva"];
+        assert_eq!(file.lines.get(&10), Some(&0));
+        assert_eq!(file.line_summary(), (8, 7));
+        assert_eq!(file.branch_summary(), (4, 3));
+    }
+
+    #[test]
+    fn lcov_round_trip() {
+        let pgm = asm::assemble("loop.lva", SOURCE, &[]).unwrap();
+        let report = report(&pgm, 1);
+        let lcov = report.to_lcov();
+        assert!(lcov.contains("BRDA:6,6,0,2\nBRDA:6,6,1,1\nBRDA:7,9,0,0\nBRDA:7,9,1,1\n"), "{}", lcov);
+        assert!(lcov.contains("DA:10,0\n"), "{}", lcov);
+        assert_eq!(CoverageReport::from_lcov(&lcov), Ok(report));
+        assert_eq!(CoverageReport::from_lcov("TN:\nSF:x\nDA:1\nend_of_record\n"), Err(CoverageError::InvalidLcov(3)));
+        assert_eq!(CoverageReport::from_lcov("TN:\nSF:x\nDA:1,1\n"), Err(CoverageError::InvalidLcov(3)));
+    }
+
+    #[test]
+    fn merge_runs() {
+        let pgm = asm::assemble("loop.lva", SOURCE, &[]).unwrap();
+        let mut merged = report(&pgm, 1);
+        merged.merge(&report(&pgm, 1));
+        assert_eq!(merged, report(&pgm, 2));
+        assert_eq!(merged.files["loop.lva"].branches[&(6, 6)], BranchCount { taken: 4, not_taken: 2 });
+        // tracefiles merge the same way, and other files are kept apart:
+        let other = asm::assemble("other.lva", "push_u8 1\nout\nfin", &[]).unwrap();
+        let lcov = format!("{}{}{}", report(&pgm, 1).to_lcov(), report(&pgm, 1).to_lcov(), report(&other, 1).to_lcov());
+        let read = CoverageReport::from_lcov(&lcov).unwrap();
+        assert_eq!(read.files["loop.lva"], merged.files["loop.lva"]);
+        assert_eq!(read.files["other.lva"].line_summary(), (3, 3));
+    }
+}
@@ -0,0 +1,193 @@
+//! Debug information, mapping bytecode back to the assembler source it was created from.
+use crate::pgm::LoadError;
+
+/// A label from the assembler source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Name of the label.
+    pub name: String,
+    /// Position in bytecode the label points to.
+    pub pos: usize,
+    /// Is the label the destination of a `call`?
+    pub function: bool,
+}
+
+/// Debug information for a program, created by the assembler.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    /// Name of the source file.
+    pub file: String,
+    /// Source lines by position in bytecode, as `(pos, line)`, sorted by position.
+    ///
+    /// There is only an entry where the line changes; it covers all bytecode up to the
+    /// next entry.
+    pub lines: Vec<(usize, usize)>,
+    /// All labels defined in the source, sorted by position.
+    pub symbols: Vec<Symbol>,
+    /// Names of the global variables, by index.
+    pub vars: Vec<String>,
+    /// Names of the local variables, by index, as `(pos, names)`, sorted by position.
+    ///
+    /// The names are valid from the position they were declared at up to the next entry.
+    pub locals: Vec<(usize, Vec<String>)>,
+}
+
+impl DebugInfo {
+    /// Adds the source line for an instruction at a position; must be called in order.
+    pub fn add_line(&mut self, pos: usize, line: usize) {
+        if self.lines.last().map(|&(_, l)| l) != Some(line) {
+            self.lines.push((pos, line));
+        }
+    }
+
+    /// Returns the source line of the instruction at a position in bytecode.
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        let ix = self.lines.partition_point(|&(pos, _)| pos <= pc);
+        if ix == 0 {
+            None
+        } else {
+            Some(self.lines[ix - 1].1)
+        }
+    }
+
+    /// Returns the position of the first instruction of a source line.
+    ///
+    /// For a line without code, like a label or a comment, that is the first instruction of
+    /// the next line that has code.
+    pub fn position(&self, line: usize) -> Option<usize> {
+        self.lines.iter().filter(|&&(_, l)| l >= line).min_by_key(|&&(_, l)| l).map(|&(pos, _)| pos)
+    }
+
+    /// Returns the name of the function a position in bytecode belongs to.
+    ///
+    /// That is the closest label before it that is a destination of `call`, or the
+    /// closest label at all, if there is no such function.
+    pub fn function(&self, pc: usize) -> Option<&str> {
+        let before = || self.symbols.iter().rev().filter(move |s| s.pos <= pc);
+        before().find(|s| s.function)
+            .or_else(|| before().next())
+            .map(|s| s.name.as_str())
+    }
+
+    /// Returns the names of the local variables valid at a position in bytecode.
+    pub fn local_names(&self, pc: usize) -> &[String] {
+        let ix = self.locals.partition_point(|(pos, _)| *pos <= pc);
+        if ix == 0 {
+            &[]
+        } else {
+            &self.locals[ix - 1].1
+        }
+    }
+
+    /// Returns the position of a label by name.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.pos)
+    }
+
+    /// Describes a position in bytecode as location in the source, like `pgm/call.lva:17 (in pow)`.
+    pub fn location(&self, pc: usize) -> Option<String> {
+        let line = self.line(pc)?;
+        Some(match self.function(pc) {
+            Some(f) => format!("{}:{} (in {})", self.file, line, f),
+            None => format!("{}:{}", self.file, line),
+        })
+    }
+
+    /// Serializes the debug information for the binary object format.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(&(self.file.len() as u32).to_be_bytes());
+        out.extend_from_slice(self.file.as_bytes());
+        out.extend_from_slice(&(self.lines.len() as u32).to_be_bytes());
+        for &(pos, line) in &self.lines {
+            out.extend_from_slice(&(pos as u32).to_be_bytes());
+            out.extend_from_slice(&(line as u32).to_be_bytes());
+        }
+        out.extend_from_slice(&(self.symbols.len() as u32).to_be_bytes());
+        for s in &self.symbols {
+            out.extend_from_slice(&(s.pos as u32).to_be_bytes());
+            out.push(s.function as u8);
+            out.push(s.name.len() as u8);
+            out.extend_from_slice(s.name.as_bytes());
+        }
+        out.extend_from_slice(&(self.vars.len() as u32).to_be_bytes());
+        for name in &self.vars {
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+        }
+        out.extend_from_slice(&(self.locals.len() as u32).to_be_bytes());
+        for (pos, names) in &self.locals {
+            out.extend_from_slice(&(*pos as u32).to_be_bytes());
+            out.push(names.len() as u8);
+            for name in names {
+                out.push(name.len() as u8);
+                out.extend_from_slice(name.as_bytes());
+            }
+        }
+        out
+    }
+
+    /// Reads debug information written by `to_bytes`.
+    pub(crate) fn from_bytes(data: &[u8]) -> Result<DebugInfo, LoadError> {
+        let mut reader = Reader { data };
+        let len = reader.u32()? as usize;
+        let file = reader.string(len)?;
+        let mut info = DebugInfo { file, ..Default::default() };
+        for _ in 0..reader.u32()? {
+            let pos = reader.u32()? as usize;
+            let line = reader.u32()? as usize;
+            info.lines.push((pos, line));
+        }
+        for _ in 0..reader.u32()? {
+            let pos = reader.u32()? as usize;
+            let function = reader.bytes(1)?[0] != 0;
+            let len = reader.bytes(1)?[0] as usize;
+            let name = reader.string(len)?;
+            info.symbols.push(Symbol { name, pos, function });
+        }
+        for _ in 0..reader.u32()? {
+            let len = reader.bytes(1)?[0] as usize;
+            info.vars.push(reader.string(len)?);
+        }
+        for _ in 0..reader.u32()? {
+            let pos = reader.u32()? as usize;
+            let mut names = vec![];
+            for _ in 0..reader.bytes(1)?[0] {
+                let len = reader.bytes(1)?[0] as usize;
+                names.push(reader.string(len)?);
+            }
+            info.locals.push((pos, names));
+        }
+        if reader.data.is_empty() {
+            Ok(info)
+        } else {
+            Err(LoadError::InvalidDebugInfo)
+        }
+    }
+}
+
+/// Helper reading values from the start of a byte slice.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        if self.data.len() < n {
+            return Err(LoadError::InvalidDebugInfo);
+        }
+        let (b, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self, n: usize) -> Result<String, LoadError> {
+        let b = self.bytes(n)?;
+        String::from_utf8(b.to_vec()).or(Err(LoadError::InvalidDebugInfo))
+    }
+}
@@ -0,0 +1,112 @@
+//! Example programs and a VM setup, shared by the unit tests.
+use crate::observer::Observer;
+use crate::syscall::{NativeFn, Syscall, SyscallTable};
+use crate::vm::{RuntimeError, Status, VM};
+use crate::Pgm;
+
+/// Native functions `run` provides, like lovas does: name, id, and number of values popped and pushed.
+pub const SYSCALLS: [(&str, u8, usize, usize); 3] = [("print", 0, 1, 0), ("max", 1, 2, 1), ("min", 2, 2, 1)];
+
+/// Names and ids of the native functions in `SYSCALLS`, for the assembler.
+pub fn syscall_names() -> Vec<(&'static str, u8)> {
+    SYSCALLS.iter().map(|&(name, id, _, _)| (name, id)).collect()
+}
+
+/// Number of values the native functions in `SYSCALLS` pop and push, by id.
+pub fn arities(id: u8) -> Option<(usize, usize)> {
+    SYSCALLS.iter().find(|s| s.1 == id).map(|&(_, _, pops, pushes)| (pops, pushes))
+}
+
+/// Looks up a native function in `SYSCALLS` by name, giving its id, pops, and pushes.
+fn syscall(name: &str) -> (usize, usize, usize) {
+    let &(_, id, pops, pushes) = SYSCALLS.iter().find(|s| s.0 == name).unwrap();
+    (id as usize, pops, pushes)
+}
+
+/// Reads every example program in `pgm/`; gives the path and the source of each.
+pub fn examples() -> Vec<(String, String)> {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/pgm");
+    let mut paths: Vec<_> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    paths.iter()
+        .filter(|p| p.extension().is_some_and(|e| e == "lva"))
+        .map(|p| (p.display().to_string(), std::fs::read_to_string(p).unwrap()))
+        .collect()
+}
+
+/// Creates a program with nothing but bytecode.
+pub fn program(text: &[u8]) -> Pgm<'static> {
+    Pgm {
+        name: "test".into(),
+        text: text.to_vec().into(),
+        vars: 0,
+        memory: 0,
+        consts: vec![].into(),
+        rodata: vec![].into(),
+        stack: None,
+        debug: None,
+    }
+}
+
+/// Collects the values a program outputs.
+#[derive(Debug, Default)]
+pub struct Output(pub Vec<i64>);
+
+impl Observer for Output {
+    fn out(&mut self, value: i64, _op_cnt: usize) {
+        self.0.push(value);
+    }
+}
+
+/// What happened when a program ran.
+#[derive(Debug, PartialEq)]
+pub struct Ran {
+    /// Values output with `OUT`.
+    pub out: Vec<i64>,
+    /// Values passed to the native function `print`.
+    pub printed: Vec<i64>,
+    /// How execution ended.
+    pub result: Result<Status, RuntimeError>,
+    /// Maximal stack depth during execution.
+    pub watermark: usize,
+}
+
+/// Runs a program with `SYSCALLS` and a stack of the given size.
+///
+/// `configure` can change the VM before the program is started. `long-loop.lva` would take
+/// forever, so execution stops after a million instructions, like lovas does.
+pub fn run(pgm: &Pgm, stack_size: usize, configure: impl FnOnce(&mut VM)) -> Ran {
+    let mut printed = vec![];
+    let mut output = Output::default();
+    let (result, watermark) = {
+        let (print_id, pops, pushes) = syscall("print");
+        let mut print = NativeFn::new(pops, pushes, |args: &[i64], _: &mut [i64]| {
+            printed.push(args[0]);
+            Ok(())
+        });
+        let (max_id, pops, pushes) = syscall("max");
+        let mut max = NativeFn::new(pops, pushes, |args: &[i64], results: &mut [i64]| {
+            results[0] = args[0].max(args[1]);
+            Ok(())
+        });
+        let (min_id, pops, pushes) = syscall("min");
+        let mut min = NativeFn::new(pops, pushes, |args: &[i64], results: &mut [i64]| {
+            results[0] = args[0].min(args[1]);
+            Ok(())
+        });
+        let mut syscalls: [Option<&mut dyn Syscall>; SYSCALLS.len()] = Default::default();
+        syscalls[print_id] = Some(&mut print);
+        syscalls[max_id] = Some(&mut max);
+        syscalls[min_id] = Some(&mut min);
+        let mut stack = vec![0; stack_size];
+        let mut memory = vec![0; pgm.memory as usize];
+        let mut vm = VM::new(&mut stack);
+        vm.memory = &mut memory;
+        vm.syscalls = SyscallTable::new(&mut syscalls);
+        vm.observer = Some(&mut output);
+        vm.instruction_limit = 1000000;
+        configure(&mut vm);
+        (vm.run(pgm).map_err(|r| r.error), vm.watermark)
+    };
+    Ran { out: output.0, printed, result, watermark }
+}
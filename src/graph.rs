@@ -0,0 +1,319 @@
+//! Graphs of a program's structure, in the DOT format of Graphviz.
+//!
+//! [`control_flow_dot`] shows the basic blocks of each function and the jumps between them,
+//! [`call_graph_dot`] which function calls which. Render the output with e.g. `dot -Tsvg`.
+//!
+//! Both work for object files, too, so they cannot build on the assembler's own tables: jump
+//! destinations are decoded from the bytecode, like `verify` does, and only the names of
+//! labels and the source lines come from the debug information the assembler left.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::{verify, Pgm};
+use crate::op::{Flow, OpInfo, Oparg};
+
+/// A single decoded instruction.
+struct Instruction<'p> {
+    info: &'static OpInfo,
+    oparg: &'p [u8],
+    /// Absolute destination, for instructions with a jump oparg.
+    dest: Option<usize>,
+}
+
+impl Instruction<'_> {
+    /// Positions execution can continue at after this instruction, without entering calls.
+    fn successors(&self, pos: usize) -> Vec<usize> {
+        let next = pos + 1 + self.oparg.len();
+        match self.info.flow {
+            Flow::Next | Flow::Call => vec![next],
+            Flow::Branch => self.dest.into_iter().chain([next]).collect(),
+            Flow::Goto => self.dest.into_iter().collect(),
+            Flow::Return | Flow::Fin => vec![],
+        }
+    }
+}
+
+/// A program's decoded bytecode, with names for its positions.
+struct Program<'p> {
+    pgm: &'p Pgm<'p>,
+    /// All instructions that could be decoded, by position.
+    instructions: BTreeMap<usize, Instruction<'p>>,
+}
+
+impl<'p> Program<'p> {
+    fn new(pgm: &'p Pgm<'p>) -> Program<'p> {
+        let text = &pgm.text[..];
+        let instructions = verify::instructions(text)
+            .map(|d| (d.pos, Instruction {
+                info: d.info,
+                oparg: &text[d.pos + 1..d.pos + d.size],
+                dest: d.dest.and_then(|d| usize::try_from(d).ok()),
+            }))
+            .collect();
+        Program { pgm, instructions }
+    }
+
+    /// Name of the label at a position, or a made up one like the disassembler uses.
+    fn name(&self, pos: usize) -> String {
+        let symbols = self.pgm.debug.as_ref().map_or(&[][..], |d| &d.symbols[..]);
+        let at = || symbols.iter().filter(|s| s.pos == pos);
+        at().find(|s| s.function).or_else(|| at().next())
+            .map_or_else(|| format!("L{}", pos), |s| s.name.clone())
+    }
+
+    /// Names of all labels at a position.
+    fn labels(&self, pos: usize) -> Vec<&str> {
+        self.pgm.debug.as_ref()
+            .map_or(vec![], |d| d.symbols.iter().filter(|s| s.pos == pos).map(|s| s.name.as_str()).collect())
+    }
+
+    /// Start of the main program and of every function called.
+    fn functions(&self) -> BTreeSet<usize> {
+        let called = self.instructions.values()
+            .filter(|i| i.info.flow == Flow::Call)
+            .filter_map(|i| i.dest)
+            .filter(|d| self.instructions.contains_key(d));
+        [0].into_iter().chain(called).collect()
+    }
+
+    /// Positions of all instructions a function can execute itself, following its jumps.
+    fn reachable(&self, entry: usize) -> BTreeSet<usize> {
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![entry];
+        while let Some(pos) = todo.pop() {
+            if let Some(i) = self.instructions.get(&pos) {
+                if seen.insert(pos) {
+                    todo.extend(i.successors(pos));
+                }
+            }
+        }
+        seen
+    }
+
+    /// Formats an instruction, with label names for jump destinations.
+    fn format(&self, pos: usize) -> String {
+        let i = &self.instructions[&pos];
+        let (name, a) = (i.info.mnemonic, i.oparg);
+        match i.info.oparg {
+            Oparg::None => String::from(name),
+            Oparg::U8 | Oparg::Syscall | Oparg::Const => format!("{} {}", name, a[0]),
+            Oparg::I8 => format!("{} {}", name, a[0] as i8),
+            Oparg::I16 => format!("{} {}", name, i16::from_be_bytes([a[0], a[1]])),
+            Oparg::I32 => format!("{} {}", name, i32::from_be_bytes([a[0], a[1], a[2], a[3]])),
+            Oparg::RoData => format!("{} D{}", name, u16::from_be_bytes([a[0], a[1]])),
+            Oparg::Global => format!("{} g{}", name, a[0]),
+            Oparg::Local => format!("{} l{}", name, a[0]),
+            Oparg::Jump => match i.dest {
+                Some(d) => format!("{} {}", name, self.name(d)),
+                None => format!("{} ?", name),
+            },
+        }
+    }
+}
+
+/// Escapes text for a quoted DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Creates a DOT graph of the basic blocks of every function and the jumps between them.
+///
+/// Every function (the main program and everything that is called) is a cluster of its
+/// own. Blocks start at the function's entry, at labels, at jump destinations, and behind
+/// jumps; they show their instructions with their positions in bytecode, and the source
+/// lines they come from. The source is only shown if it is given.
+pub fn control_flow_dot(pgm: &Pgm, source: Option<&str>) -> String {
+    let program = Program::new(pgm);
+    let debug = pgm.debug.as_ref();
+    let lines: Vec<&str> = source.map_or(vec![], |s| s.lines().collect());
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", escape(&pgm.name)).unwrap();
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+    for entry in program.functions() {
+        let reachable = program.reachable(entry);
+        // find the instructions that start a basic block:
+        let mut leaders: BTreeSet<usize> = BTreeSet::from([entry]);
+        for &pos in &reachable {
+            let i = &program.instructions[&pos];
+            if !program.labels(pos).is_empty() {
+                leaders.insert(pos);
+            }
+            if matches!(i.info.flow, Flow::Branch | Flow::Goto | Flow::Return | Flow::Fin) {
+                leaders.extend(i.successors(pos));
+                leaders.insert(pos + 1 + i.oparg.len());
+            }
+        }
+        // split the function into blocks, by their first position:
+        let mut blocks: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut current = None;
+        for &pos in &reachable {
+            let continues = current.is_some_and(|(_, next)| next == pos);
+            if leaders.contains(&pos) || !continues {
+                current = Some((pos, pos));
+            }
+            let (start, _) = current.unwrap();
+            blocks.entry(start).or_default().push(pos);
+            current = Some((start, pos + 1 + program.instructions[&pos].oparg.len()));
+        }
+
+        let node = |pos: usize| format!("f{}_{}", entry, pos);
+        writeln!(out, "  subgraph \"cluster_{}\" {{", entry).unwrap();
+        writeln!(out, "    label=\"{}\";", escape(&program.name(entry))).unwrap();
+        for (&start, block) in &blocks {
+            let mut label = String::new();
+            for name in program.labels(start) {
+                write!(label, "{}:\\l", escape(name)).unwrap();
+            }
+            let mut last_line = None;
+            for &pos in block {
+                write!(label, "{:>4}  {}", pos, escape(&program.format(pos))).unwrap();
+                let line = debug.and_then(|d| d.line(pos));
+                if let Some(line) = line.filter(|&l| Some(l) != last_line) {
+                    match lines.get(line - 1) {
+                        Some(text) => write!(label, "    # {}: {}", line, escape(text.trim())).unwrap(),
+                        None => write!(label, "    # line {}", line).unwrap(),
+                    }
+                }
+                last_line = line;
+                label.push_str("\\l");
+            }
+            writeln!(out, "    {} [label=\"{}\"];", node(start), label).unwrap();
+        }
+        for (&start, block) in &blocks {
+            let &pos = block.last().unwrap();
+            let i = &program.instructions[&pos];
+            let next = pos + 1 + i.oparg.len();
+            match i.info.flow {
+                Flow::Branch => {
+                    if let Some(d) = i.dest.filter(|d| blocks.contains_key(d)) {
+                        writeln!(out, "    {} -> {} [label=\"{}\"];", node(start), node(d), i.info.mnemonic).unwrap();
+                    }
+                    if blocks.contains_key(&next) {
+                        writeln!(out, "    {} -> {} [label=\"else\", style=dashed];", node(start), node(next)).unwrap();
+                    }
+                },
+                Flow::Goto => if let Some(d) = i.dest.filter(|d| blocks.contains_key(d)) {
+                    writeln!(out, "    {} -> {};", node(start), node(d)).unwrap();
+                },
+                Flow::Next | Flow::Call => if blocks.contains_key(&next) {
+                    writeln!(out, "    {} -> {};", node(start), node(next)).unwrap();
+                },
+                Flow::Return | Flow::Fin => {},
+            }
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Creates a DOT graph of the functions and which of them call which.
+///
+/// Nodes are the main program and every function called, with the source location of their
+/// start. Edges are labelled with the number of `call` instructions, if there is more than one.
+pub fn call_graph_dot(pgm: &Pgm) -> String {
+    let program = Program::new(pgm);
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", escape(&pgm.name)).unwrap();
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+    let functions = program.functions();
+    for &entry in &functions {
+        let mut label = program.name(entry);
+        if let Some(location) = pgm.debug.as_ref().and_then(|d| d.line(entry).map(|l| format!("{}:{}", d.file, l))) {
+            write!(label, "\n{}", location).unwrap();
+        }
+        writeln!(out, "  f{} [label=\"{}\"];", entry, escape(&label).replace('\n', "\\n")).unwrap();
+    }
+    for &entry in &functions {
+        // count the calls to each function:
+        let mut calls: BTreeMap<usize, usize> = BTreeMap::new();
+        for pos in program.reachable(entry) {
+            let i = &program.instructions[&pos];
+            if let Some(dest) = i.dest.filter(|_| i.info.flow == Flow::Call) {
+                *calls.entry(dest).or_default() += 1;
+            }
+        }
+        for (dest, n) in calls.into_iter().filter(|(d, _)| functions.contains(d)) {
+            if n > 1 {
+                writeln!(out, "  f{} -> f{} [label=\"{}\"];", entry, dest, n).unwrap();
+            } else {
+                writeln!(out, "  f{} -> f{};", entry, dest).unwrap();
+            }
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// The edges in a DOT graph.
+    fn edges(dot: &str) -> Vec<&str> {
+        dot.lines().map(str::trim).filter(|l| l.contains(" -> ")).collect()
+    }
+
+    #[test]
+    fn branch_edges() {
+        let source = "push_u8 1\nifeq zero\npush_u8 2\ngoto done\nzero:\npush_u8 3\ndone:\nout\nfin\n";
+        let pgm = asm::assemble("branch", source, &[]).unwrap();
+        assert_eq!(edges(&control_flow_dot(&pgm, None)), vec![
+            "f0_0 -> f0_10 [label=\"ifeq\"];",
+            "f0_0 -> f0_5 [label=\"else\", style=dashed];",
+            "f0_5 -> f0_12;",
+            "f0_10 -> f0_12;",
+        ]);
+        let dot = control_flow_dot(&pgm, Some(source));
+        assert!(dot.contains("f0_10 [label=\"zero:\\l  10  push_u8 3    # 6: push_u8 3\\l\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn loop_edges() {
+        let source = "push_u8 3\nloop:\npush_u8 1\nsub\ndup\nifgt loop\nout\nfin\n";
+        let pgm = asm::assemble("loop", source, &[]).unwrap();
+        let expected = vec![
+            "f0_0 -> f0_2;",
+            "f0_2 -> f0_2 [label=\"ifgt\"];",
+            "f0_2 -> f0_9 [label=\"else\", style=dashed];",
+        ];
+        assert_eq!(edges(&control_flow_dot(&pgm, None)), expected);
+        // object files need not have debug information, the jumps are in the bytecode:
+        let stripped = Pgm { debug: None, ..pgm };
+        let dot = control_flow_dot(&stripped, None);
+        assert_eq!(edges(&dot), expected);
+        assert!(dot.contains("ifgt L2"), "{}", dot);
+    }
+
+    #[test]
+    fn call_edges() {
+        let source = "\
+push_u8 0
+call f
+push_u8 0
+call f
+push_u8 0
+call g
+fin
+f:
+push_u8 0
+call g
+ret
+g:
+ret
+";
+        let pgm = asm::assemble("call", source, &[]).unwrap();
+        let dot = call_graph_dot(&pgm);
+        assert!(dot.contains("f16 [label=\"f\\ncall:9\"];"), "{}", dot);
+        assert_eq!(edges(&dot), vec![
+            "f0 -> f16 [label=\"2\"];",
+            "f0 -> f22;",
+            "f16 -> f22;",
+        ]);
+        // a call does not end a basic block, every function is a single one:
+        let dot = control_flow_dot(&pgm, None);
+        assert!(edges(&dot).is_empty(), "{}", dot);
+        assert!(dot.contains("subgraph \"cluster_16\" {\n    label=\"f\";"), "{}", dot);
+        assert!(dot.contains("subgraph \"cluster_22\" {\n    label=\"g\";"), "{}", dot);
+    }
+}
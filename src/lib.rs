@@ -1,12 +1,33 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
 pub mod op;
 pub mod pgm;
 pub mod vm;
+pub mod syscall;
+pub mod observer;
+pub mod snapshot;
+#[cfg(feature = "asm")]
 pub mod asm;
+pub mod verify;
+#[cfg(feature = "std")]
+pub mod debug;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod stack;
+#[cfg(feature = "std")]
+pub mod graph;
+#[cfg(all(test, feature = "std"))]
+mod fixture;
 
+#[cfg(feature = "asm")]
 extern crate regex;
+#[cfg(feature = "asm")]
 extern crate lazy_static;
+#[cfg(feature = "asm")]
 extern crate parse_int;
-extern crate clap;
 
 // re-export main types
 pub use crate::pgm::Pgm;
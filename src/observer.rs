@@ -0,0 +1,142 @@
+//! Observing what happens inside the VM.
+//!
+//! The VM does not print anything itself. Values a program outputs with `OUT` and the
+//! trace log are reported to an `Observer` the VM holds, which decides what to do with them.
+use core::fmt::{Debug, Formatter};
+#[cfg(feature = "std")]
+use crate::op;
+
+/// Something that happened during execution, reported while the VM's `trace` is active.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent<'a> {
+    /// An instruction is about to be executed.
+    Instruction {
+        /// Position of the instruction in the bytecode.
+        pc: usize,
+        /// Opcode of the instruction.
+        opcode: u8,
+        /// Frame base at the time.
+        fb: usize,
+        /// Number of instructions executed so far, including this one.
+        op_cnt: usize,
+        /// Complete value stack at the time.
+        stack: &'a [i64],
+    },
+    /// A relative jump is executed.
+    Jump {
+        /// Program counter before the jump (pointing behind the jump instruction).
+        from: usize,
+        /// Relative distance of the jump.
+        delta: i16,
+    },
+    /// A function was called with `CALL`.
+    Call {
+        /// Address execution will return to.
+        ret: usize,
+        /// Address of the function called.
+        dest: usize,
+        /// Number of values passed to the function.
+        args: usize,
+    },
+    /// A function returned with `RET`.
+    Return {
+        /// Address execution continues at.
+        to: usize,
+    },
+    /// The program terminated with `FIN`.
+    Terminated {
+        /// Number of instructions executed.
+        op_cnt: usize,
+        /// Complete value stack at the end.
+        stack: &'a [i64],
+    },
+}
+
+/// Receives output and trace events from the VM.
+pub trait Observer {
+    /// Called for every value a program outputs with `OUT`.
+    fn out(&mut self, value: i64, op_cnt: usize);
+
+    /// Called for every event while the VM's `trace` is active.
+    ///
+    /// Does nothing by default.
+    fn trace(&mut self, event: &TraceEvent) {
+        let _ = event;
+    }
+}
+
+impl Debug for dyn Observer + '_ {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Observer")
+    }
+}
+
+/// Observer that prints everything to stdout.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct StdoutObserver;
+
+#[cfg(feature = "std")]
+impl Observer for StdoutObserver {
+    fn out(&mut self, value: i64, op_cnt: usize) {
+        println!("Out: {} (@{})", value, op_cnt);
+    }
+
+    fn trace(&mut self, event: &TraceEvent) {
+        match event {
+            TraceEvent::Instruction { pc, opcode, fb, op_cnt, stack } => {
+                println!("pc={}, fb={}, op_cnt={}, stack={:?}", pc, fb, op_cnt, stack);
+                let mnemonic = op::info(*opcode).map_or("?", |i| i.mnemonic);
+                println!("Executing op 0x{:02x} ({})", opcode, mnemonic);
+            },
+            TraceEvent::Jump { from, delta } => {
+                println!("  Jump from {} by {}", from, delta);
+            },
+            TraceEvent::Call { ret, dest, args } => {
+                println!("  Call to {} with {} args, returning to {}", dest, args, ret);
+            },
+            TraceEvent::Return { to } => {
+                println!("  Return to {}", to);
+            },
+            TraceEvent::Terminated { op_cnt, stack } => {
+                println!("Terminated!");
+                println!("op_cnt={}, stack={:?}", op_cnt, stack);
+            },
+        }
+    }
+}
+
+/// Passes the program's output, and optionally trace events, on to another observer.
+///
+/// Observers that only collect data (like `profile::Profiler`) use this to sit in front of
+/// the observer that shows the output, so that several of them can be chained.
+#[derive(Debug, Default)]
+pub struct Forward<'o> {
+    /// Observer that receives everything passed on.
+    pub inner: Option<&'o mut dyn Observer>,
+    /// Pass trace events on, too? Output is always passed on.
+    pub forward_trace: bool,
+}
+
+impl<'o> Forward<'o> {
+    /// Passes output on to `inner`, but no trace events.
+    pub fn new(inner: Option<&'o mut dyn Observer>) -> Forward<'o> {
+        Forward { inner, forward_trace: false }
+    }
+}
+
+impl Observer for Forward<'_> {
+    fn out(&mut self, value: i64, op_cnt: usize) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.out(value, op_cnt);
+        }
+    }
+
+    fn trace(&mut self, event: &TraceEvent) {
+        if self.forward_trace {
+            if let Some(inner) = self.inner.as_mut() {
+                inner.trace(event);
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
 //! Module holding the constants defining the opcodes for the VM.
+//!
+//! Besides the constants, the module holds the table `OPS` describing every instruction
+//! (mnemonic, oparg layout, stack effect). The assembler, the disassembler, the verifier and
+//! the trace output all use it, so there is a single place that defines an instruction.
 
 /// opcode: Do nothing. No oparg.
 ///
@@ -60,6 +64,44 @@ pub const SWAP_L: u8 = 0x08;
 /// oparg: 0
 pub const OUT: u8 = 0x09;
 
+/// opcode: Call a native function provided by the host.
+///
+/// pop: n, push: m (as declared by the native function)
+/// oparg: 1B, u8 id of native function
+pub const SYSCALL: u8 = 0x0a;
+
+/// opcode: Push immediate signed value to stack.
+///
+/// pop: 0, push: 1
+/// oparg: 1B, i8 value to push
+pub const PUSH_I8: u8 = 0x0b;
+
+/// opcode: Push immediate signed value to stack.
+///
+/// pop: 0, push: 1
+/// oparg: 2B, i16 value to push
+pub const PUSH_I16: u8 = 0x0c;
+
+/// opcode: Push immediate signed value to stack.
+///
+/// pop: 0, push: 1
+/// oparg: 4B, i32 value to push
+pub const PUSH_I32: u8 = 0x0d;
+
+/// opcode: Push value from the program's constant pool to stack.
+///
+/// pop: 0, push: 1
+/// oparg: 1B, u8 index of constant
+pub const PUSH_C: u8 = 0x0e;
+
+/// opcode: Read an element of a table in the read-only data segment. Pops index.
+///
+/// Bytes are zero extended.
+///
+/// pop: 1, push: 1
+/// oparg: 2B, u16 position of the table in the read-only data segment
+pub const LOAD_RO: u8 = 0x0f;
+
 /// opcode: Add top two values on stack.
 ///
 /// pop: 2, push: 1
@@ -90,8 +132,108 @@ pub const DIV: u8 = 0x13;
 /// oparg: 0
 pub const MOD: u8 = 0x14;
 
+/// opcode: Swap the top two values on stack.
+///
+/// pop: 2, push: 2
+/// oparg: 0
 pub const ROT: u8 = 0x15;
 
+/// opcode: Bitwise and of top two values on stack.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const AND: u8 = 0x30;
+
+/// opcode: Bitwise or of top two values on stack.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const OR: u8 = 0x31;
+
+/// opcode: Bitwise exclusive or of top two values on stack.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const XOR: u8 = 0x32;
+
+/// opcode: Bitwise complement of top value on stack.
+///
+/// pop: 1, push: 1
+/// oparg: 0
+pub const NOT: u8 = 0x33;
+
+/// opcode: Shift left. Pops shift amount, then value.
+///
+/// Shift amounts outside of 0..=63 give 0.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const SHL: u8 = 0x34;
+
+/// opcode: Logical shift right (fills with zeros). Pops shift amount, then value.
+///
+/// Shift amounts outside of 0..=63 give 0.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const SHR: u8 = 0x35;
+
+/// opcode: Arithmetic shift right (fills with sign bit). Pops shift amount, then value.
+///
+/// Shift amounts outside of 0..=63 give -1 for negative values, 0 otherwise.
+///
+/// pop: 2, push: 1
+/// oparg: 0
+pub const SAR: u8 = 0x36;
+
+/// opcode: Read a byte from memory. Pops address.
+///
+/// The value is zero extended. Memory is little endian.
+///
+/// pop: 1, push: 1
+/// oparg: 0
+pub const LOAD8: u8 = 0x40;
+
+/// opcode: Write a byte to memory. Pops value, then address.
+///
+/// Only the lowest 8 bits of the value are written.
+///
+/// pop: 2, push: 0
+/// oparg: 0
+pub const STORE8: u8 = 0x41;
+
+/// opcode: Read two bytes from memory. Pops address.
+///
+/// The value is zero extended. Memory is little endian.
+///
+/// pop: 1, push: 1
+/// oparg: 0
+pub const LOAD16: u8 = 0x42;
+
+/// opcode: Write two bytes to memory. Pops value, then address.
+///
+/// Only the lowest 16 bits of the value are written. Memory is little endian.
+///
+/// pop: 2, push: 0
+/// oparg: 0
+pub const STORE16: u8 = 0x43;
+
+/// opcode: Read four bytes from memory. Pops address.
+///
+/// The value is zero extended. Memory is little endian.
+///
+/// pop: 1, push: 1
+/// oparg: 0
+pub const LOAD32: u8 = 0x44;
+
+/// opcode: Write four bytes to memory. Pops value, then address.
+///
+/// Only the lowest 32 bits of the value are written. Memory is little endian.
+///
+/// pop: 2, push: 0
+/// oparg: 0
+pub const STORE32: u8 = 0x45;
+
 /// opcode: Relative jump.
 ///
 /// pop: 0, push: 0
@@ -151,3 +293,145 @@ pub const RET: u8 = 0x28;
 /// pop: 0, push: 0
 /// oparg: 0
 pub const FIN: u8 = 0xff;
+
+/// Layout and meaning of an instruction's oparg.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oparg {
+    /// No oparg.
+    None,
+    /// 1B, u8 immediate value.
+    U8,
+    /// 1B, i8 immediate value.
+    I8,
+    /// 2B, i16 immediate value.
+    I16,
+    /// 4B, i32 immediate value.
+    I32,
+    /// 1B, u8 index into the constant pool.
+    Const,
+    /// 2B, u16 position of an entry in the read-only data segment.
+    RoData,
+    /// 1B, u8 index of global variable.
+    Global,
+    /// 1B, u8 index of local variable.
+    Local,
+    /// 1B, u8 id of native function.
+    Syscall,
+    /// 2B, i16 relative jump.
+    Jump,
+}
+
+impl Oparg {
+    /// Number of bytes the oparg takes in bytecode.
+    pub const fn size(self) -> usize {
+        match self {
+            Oparg::None => 0,
+            Oparg::U8 | Oparg::I8 | Oparg::Const | Oparg::Global | Oparg::Local | Oparg::Syscall => 1,
+            Oparg::I16 | Oparg::RoData | Oparg::Jump => 2,
+            Oparg::I32 => 4,
+        }
+    }
+}
+
+/// How an instruction influences where execution continues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the next instruction.
+    Next,
+    /// Always jumps.
+    Goto,
+    /// Jumps or continues with the next instruction, depending on a condition.
+    Branch,
+    /// Jumps into a function, that returns to the next instruction.
+    Call,
+    /// Returns from a function.
+    Return,
+    /// Terminates the program.
+    Fin,
+}
+
+/// Metadata describing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpInfo {
+    /// Name of the instruction in assembler source.
+    pub mnemonic: &'static str,
+    /// Opcode in bytecode.
+    pub opcode: u8,
+    /// Oparg following the opcode.
+    pub oparg: Oparg,
+    /// Number of values popped from stack.
+    ///
+    /// `CALL` passes additional values to the function; `SYSCALL` pops as many values as
+    /// the native function declares.
+    pub pops: u8,
+    /// Number of values pushed to stack.
+    ///
+    /// `SYSCALL` pushes as many values as the native function declares.
+    pub pushes: u8,
+    /// Effect on control flow.
+    pub flow: Flow,
+}
+
+/// Shorthand to keep the table readable.
+const fn def(mnemonic: &'static str, opcode: u8, oparg: Oparg, pops: u8, pushes: u8, flow: Flow) -> OpInfo {
+    OpInfo { mnemonic, opcode, oparg, pops, pushes, flow }
+}
+
+/// Table describing every instruction of the VM.
+pub static OPS: [OpInfo; 45] = [
+    def("nop", NOP, Oparg::None, 0, 0, Flow::Next),
+    def("pop", POP, Oparg::None, 1, 0, Flow::Next),
+    def("push_u8", PUSH_U8, Oparg::U8, 0, 1, Flow::Next),
+    def("push_i8", PUSH_I8, Oparg::I8, 0, 1, Flow::Next),
+    def("push_i16", PUSH_I16, Oparg::I16, 0, 1, Flow::Next),
+    def("push_i32", PUSH_I32, Oparg::I32, 0, 1, Flow::Next),
+    def("push_c", PUSH_C, Oparg::Const, 0, 1, Flow::Next),
+    def("dup", DUP, Oparg::None, 1, 2, Flow::Next),
+    def("store", STORE, Oparg::Global, 1, 0, Flow::Next),
+    def("load", LOAD, Oparg::Global, 0, 1, Flow::Next),
+    def("store_l", STORE_L, Oparg::Local, 1, 0, Flow::Next),
+    def("load_l", LOAD_L, Oparg::Local, 0, 1, Flow::Next),
+    def("swap_l", SWAP_L, Oparg::Local, 1, 1, Flow::Next),
+    def("out", OUT, Oparg::None, 1, 0, Flow::Next),
+    def("syscall", SYSCALL, Oparg::Syscall, 0, 0, Flow::Next),
+    def("load_ro", LOAD_RO, Oparg::RoData, 1, 1, Flow::Next),
+    def("add", ADD, Oparg::None, 2, 1, Flow::Next),
+    def("sub", SUB, Oparg::None, 2, 1, Flow::Next),
+    def("mul", MUL, Oparg::None, 2, 1, Flow::Next),
+    def("div", DIV, Oparg::None, 2, 1, Flow::Next),
+    def("mod", MOD, Oparg::None, 2, 1, Flow::Next),
+    def("rot", ROT, Oparg::None, 2, 2, Flow::Next),
+    def("and", AND, Oparg::None, 2, 1, Flow::Next),
+    def("or", OR, Oparg::None, 2, 1, Flow::Next),
+    def("xor", XOR, Oparg::None, 2, 1, Flow::Next),
+    def("not", NOT, Oparg::None, 1, 1, Flow::Next),
+    def("shl", SHL, Oparg::None, 2, 1, Flow::Next),
+    def("shr", SHR, Oparg::None, 2, 1, Flow::Next),
+    def("sar", SAR, Oparg::None, 2, 1, Flow::Next),
+    def("load8", LOAD8, Oparg::None, 1, 1, Flow::Next),
+    def("store8", STORE8, Oparg::None, 2, 0, Flow::Next),
+    def("load16", LOAD16, Oparg::None, 1, 1, Flow::Next),
+    def("store16", STORE16, Oparg::None, 2, 0, Flow::Next),
+    def("load32", LOAD32, Oparg::None, 1, 1, Flow::Next),
+    def("store32", STORE32, Oparg::None, 2, 0, Flow::Next),
+    def("goto", GOTO, Oparg::Jump, 0, 0, Flow::Goto),
+    def("ifeq", IFEQ, Oparg::Jump, 1, 0, Flow::Branch),
+    def("ifne", IFNE, Oparg::Jump, 1, 0, Flow::Branch),
+    def("iflt", IFLT, Oparg::Jump, 1, 0, Flow::Branch),
+    def("ifle", IFLE, Oparg::Jump, 1, 0, Flow::Branch),
+    def("ifgt", IFGT, Oparg::Jump, 1, 0, Flow::Branch),
+    def("ifge", IFGE, Oparg::Jump, 1, 0, Flow::Branch),
+    def("call", CALL, Oparg::Jump, 1, 0, Flow::Call),
+    def("ret", RET, Oparg::None, 0, 0, Flow::Return),
+    def("fin", FIN, Oparg::None, 0, 0, Flow::Fin),
+];
+
+/// Looks up the description of an instruction by opcode.
+pub fn info(opcode: u8) -> Option<&'static OpInfo> {
+    OPS.iter().find(|i| i.opcode == opcode)
+}
+
+/// Looks up the description of an instruction by its mnemonic.
+pub fn by_mnemonic(mnemonic: &str) -> Option<&'static OpInfo> {
+    OPS.iter().find(|i| i.mnemonic == mnemonic)
+}
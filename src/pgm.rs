@@ -1,10 +1,500 @@
+//! Programs to be executed in the VM, and their binary object format.
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use crate::debug::DebugInfo;
+
+/// Bytes held by a program.
+///
+/// With `std`, a program can own its data or borrow it. Without, it always borrows.
+#[cfg(feature = "std")]
+pub type Bytes<'a> = Cow<'a, [u8]>;
+/// Bytes held by a program.
+///
+/// With `std`, a program can own its data or borrow it. Without, it always borrows.
+#[cfg(not(feature = "std"))]
+pub type Bytes<'a> = &'a [u8];
+
+/// A string held by a program, owned or borrowed like `Bytes`.
+#[cfg(feature = "std")]
+pub type Str<'a> = Cow<'a, str>;
+/// A string held by a program, owned or borrowed like `Bytes`.
+#[cfg(not(feature = "std"))]
+pub type Str<'a> = &'a str;
+
+/// Gives the content of `Bytes`, whether they are owned or borrowed.
+fn slice<'b>(bytes: &'b Bytes<'_>) -> &'b [u8] {
+    bytes
+}
+
+/// Wraps borrowed bytes as `Bytes`.
+#[cfg(feature = "std")]
+fn borrowed(data: &[u8]) -> Bytes<'_> {
+    Cow::Borrowed(data)
+}
+/// Wraps borrowed bytes as `Bytes`.
+#[cfg(not(feature = "std"))]
+fn borrowed(data: &[u8]) -> Bytes<'_> {
+    data
+}
+
+/// Wraps a borrowed string as `Str`.
+#[cfg(feature = "std")]
+fn borrowed_str(s: &str) -> Str<'_> {
+    Cow::Borrowed(s)
+}
+/// Wraps a borrowed string as `Str`.
+#[cfg(not(feature = "std"))]
+fn borrowed_str(s: &str) -> Str<'_> {
+    s
+}
+
 /// Holds a program to be executed in VM.
 #[derive(Debug)]
-pub struct Pgm {
+pub struct Pgm<'a> {
     /// Some name identifying the program.
-    pub name: String,
+    pub name: Str<'a>,
     /// Bytecode holding the program's instructions.
-    pub text: Vec<u8>,
+    pub text: Bytes<'a>,
     /// Number of global variables in program.
     pub vars: u8,
+    /// Number of bytes of memory the program needs for `LOAD8`, `STORE8` and friends.
+    pub memory: u32,
+    /// Constant pool, read by `PUSH_C`.
+    ///
+    /// Holds i64 values, 8 bytes each, big endian; use `constant` to read them.
+    pub consts: Bytes<'a>,
+    /// Read-only data segment, read by `LOAD_RO`.
+    ///
+    /// Holds a sequence of entries (tables or strings), see `RoEntry`.
+    pub rodata: Bytes<'a>,
+    /// Number of stack cells the program needs at most, if that is known.
+    ///
+    /// This is found by `stack::max_depth`; hosts can use it to give the VM a stack of the
+    /// right size.
+    pub stack: Option<u32>,
+    /// Optional information mapping bytecode back to its source.
+    #[cfg(feature = "std")]
+    pub debug: Option<DebugInfo>,
+}
+
+/// Magic number at the start of every serialized program.
+pub const MAGIC: [u8; 4] = *b"LOVM";
+
+/// Version of the serialized program format written by `Pgm::to_bytes`.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Section tag: name of the program (UTF-8).
+const SECTION_NAME: u8 = 0x01;
+/// Section tag: bytecode.
+const SECTION_TEXT: u8 = 0x02;
+/// Section tag: debug information (optional).
+const SECTION_DEBUG: u8 = 0x03;
+/// Section tag: constant pool (optional).
+const SECTION_CONSTS: u8 = 0x04;
+/// Section tag: size of memory needed, as u32 (optional).
+const SECTION_MEMORY: u8 = 0x05;
+/// Section tag: read-only data segment (optional).
+const SECTION_RODATA: u8 = 0x06;
+/// Section tag: number of stack cells needed, as u32 (optional).
+const SECTION_STACK: u8 = 0x07;
+
+/// An error that happens when reading a serialized program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    Truncated,
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    InvalidChecksum,
+    UnknownSection(u8),
+    DuplicateSection(u8),
+    MissingSection(u8),
+    InvalidName,
+    InvalidDebugInfo,
+    InvalidConstants,
+    InvalidMemorySize,
+    InvalidStackSize,
+    InvalidRoData,
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LoadError {
+}
+
+/// Calculates the CRC-32 (IEEE 802.3) checksum of some bytes.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xffffffff, data)
+}
+
+/// Feeds more bytes into a running CRC-32 calculation.
+///
+/// Start with `0xffffffff` and invert the final value, to get the checksum of all bytes.
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    crc
+}
+
+/// An entry in a program's read-only data segment: a table of values, or a string.
+///
+/// In the segment, every entry starts with a header of three bytes: the width of its
+/// elements (1 for unsigned bytes, 8 for i64 values), followed by the number of elements
+/// as u16. The elements follow directly, big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoEntry<'d> {
+    /// Position of the entry's header in the segment.
+    pub offset: usize,
+    /// Number of bytes per element, 1 or 8.
+    pub width: u8,
+    /// The elements.
+    pub data: &'d [u8],
+}
+
+impl<'d> RoEntry<'d> {
+    /// Size of the header in front of every entry.
+    pub const HEADER_SIZE: usize = 3;
+
+    /// Reads the entry starting at a position in the read-only data segment.
+    pub fn parse(rodata: &'d [u8], offset: usize) -> Option<RoEntry<'d>> {
+        let header = rodata.get(offset..offset.checked_add(Self::HEADER_SIZE)?)?;
+        let width = header[0];
+        if width != 1 && width != 8 {
+            return None;
+        }
+        let count = u16::from_be_bytes([header[1], header[2]]) as usize;
+        let start = offset + Self::HEADER_SIZE;
+        let data = rodata.get(start..start + count * width as usize)?;
+        Some(RoEntry { offset, width, data })
+    }
+
+    /// Number of elements in the entry.
+    pub fn len(&self) -> usize {
+        self.data.len() / self.width as usize
+    }
+
+    /// Does the entry have no elements?
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Position of the next entry in the segment.
+    pub fn end(&self) -> usize {
+        self.offset + Self::HEADER_SIZE + self.data.len()
+    }
+
+    /// Reads an element; bytes are zero extended.
+    pub fn get(&self, index: usize) -> Option<i64> {
+        let width = self.width as usize;
+        let start = index.checked_mul(width)?;
+        let b = self.data.get(start..start.checked_add(width)?)?;
+        if width == 1 {
+            Some(b[0] as i64)
+        } else {
+            Some(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        }
+    }
+}
+
+/// Iterator over the entries of a read-only data segment.
+///
+/// Stops at the end of the segment, or at the first invalid entry.
+#[derive(Debug, Clone)]
+pub struct RoEntries<'d> {
+    rodata: &'d [u8],
+    offset: usize,
+}
+
+impl<'d> Iterator for RoEntries<'d> {
+    type Item = RoEntry<'d>;
+
+    fn next(&mut self) -> Option<RoEntry<'d>> {
+        let entry = RoEntry::parse(self.rodata, self.offset)?;
+        self.offset = entry.end();
+        Some(entry)
+    }
+}
+
+/// Reads a big endian u32 from the start of some bytes.
+fn read_u32(data: &[u8]) -> Result<u32, LoadError> {
+    match data.get(..4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => Err(LoadError::Truncated),
+    }
+}
+
+impl<'a> Pgm<'a> {
+    /// Number of values in the constant pool.
+    pub fn const_count(&self) -> usize {
+        self.consts.len() / 8
+    }
+
+    /// Calculates a hash identifying the program's executable content.
+    ///
+    /// The hash covers everything that influences execution (bytecode, variables, memory size,
+    /// constants and read-only data), but not name or debug information.
+    pub fn identity(&self) -> u32 {
+        let mut crc = crc32_update(0xffffffff, &[self.vars]);
+        crc = crc32_update(crc, &self.memory.to_be_bytes());
+        for data in [&self.text, &self.consts, &self.rodata] {
+            crc = crc32_update(crc, &(data.len() as u32).to_be_bytes());
+            crc = crc32_update(crc, data);
+        }
+        !crc
+    }
+
+    /// Does an instruction start at a position in the bytecode?
+    ///
+    /// The end of the bytecode counts as well, execution can get there after the last
+    /// instruction.
+    pub fn is_instruction_start(&self, pos: usize) -> bool {
+        let mut p = 0;
+        while p < pos {
+            match self.text.get(p).and_then(|&o| crate::op::info(o)) {
+                Some(info) => p += 1 + info.oparg.size(),
+                None => return false,
+            }
+        }
+        p == pos && pos <= self.text.len()
+    }
+
+    /// Reads the entry at a position in the read-only data segment.
+    pub fn rodata_entry(&self, offset: usize) -> Option<RoEntry<'_>> {
+        RoEntry::parse(slice(&self.rodata), offset)
+    }
+
+    /// Iterates over all entries in the read-only data segment.
+    pub fn rodata_entries(&self) -> RoEntries<'_> {
+        RoEntries { rodata: slice(&self.rodata), offset: 0 }
+    }
+
+    /// Reads a value from the constant pool.
+    pub fn constant(&self, idx: u8) -> Option<i64> {
+        let start = idx as usize * 8;
+        let b = self.consts.get(start..start + 8)?;
+        Some(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    /// Serializes the program into the binary object format.
+    ///
+    /// The format starts with a header (`MAGIC`, `FORMAT_VERSION`, number of global
+    /// variables), followed by sections (tag byte, u32 length, data), and ends with the
+    /// CRC-32 of everything before it. All numbers are big endian.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.text.len() + self.name.len() + 20);
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(self.vars);
+        let debug = self.debug.as_ref().map(|d| d.to_bytes());
+        let memory = self.memory.to_be_bytes();
+        let stack = self.stack.map(|s| s.to_be_bytes());
+        let mut sections = vec![(SECTION_NAME, self.name.as_bytes()), (SECTION_TEXT, &self.text)];
+        if self.memory != 0 {
+            sections.push((SECTION_MEMORY, &memory));
+        }
+        if !self.consts.is_empty() {
+            sections.push((SECTION_CONSTS, &self.consts));
+        }
+        if !self.rodata.is_empty() {
+            sections.push((SECTION_RODATA, &self.rodata));
+        }
+        if let Some(stack) = &stack {
+            sections.push((SECTION_STACK, stack));
+        }
+        if let Some(debug) = &debug {
+            sections.push((SECTION_DEBUG, debug));
+        }
+        for (tag, data) in sections {
+            out.push(tag);
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        let crc = crc32(&out);
+        out.extend_from_slice(&crc.to_be_bytes());
+        out
+    }
+
+    /// Reads a program from the binary object format written by `to_bytes`.
+    ///
+    /// The container is validated completely (magic, version, sections, checksum). The
+    /// program borrows name, bytecode and data segments from `data`, nothing is copied. Debug
+    /// information is only read with `std`; without, it is skipped.
+    pub fn from_bytes(data: &'a [u8]) -> Result<Pgm<'a>, LoadError> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            return Err(LoadError::InvalidMagic);
+        }
+        if data.len() < 10 {
+            return Err(LoadError::Truncated);
+        }
+        let (body, crc) = data.split_at(data.len() - 4);
+        if crc32(body) != read_u32(crc)? {
+            return Err(LoadError::InvalidChecksum);
+        }
+        if body[4] != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(body[4]));
+        }
+        let vars = body[5];
+        let mut name = None;
+        let mut text = None;
+        let mut debug = None;
+        let mut consts = None;
+        let mut memory = None;
+        let mut rodata = None;
+        let mut stack = None;
+        let mut rest = &body[6..];
+        while !rest.is_empty() {
+            let tag = rest[0];
+            let len = read_u32(&rest[1..])? as usize;
+            let end = len.checked_add(5).ok_or(LoadError::Truncated)?;
+            let section = rest.get(5..end).ok_or(LoadError::Truncated)?;
+            rest = &rest[end..];
+            let slot = match tag {
+                SECTION_NAME => &mut name,
+                SECTION_TEXT => &mut text,
+                SECTION_DEBUG => &mut debug,
+                SECTION_CONSTS => &mut consts,
+                SECTION_MEMORY => &mut memory,
+                SECTION_RODATA => &mut rodata,
+                SECTION_STACK => &mut stack,
+                _ => return Err(LoadError::UnknownSection(tag)),
+            };
+            if slot.replace(section).is_some() {
+                return Err(LoadError::DuplicateSection(tag));
+            }
+        }
+        let name = name.ok_or(LoadError::MissingSection(SECTION_NAME))?;
+        let name = core::str::from_utf8(name).or(Err(LoadError::InvalidName))?;
+        let text = text.ok_or(LoadError::MissingSection(SECTION_TEXT))?;
+        let consts: &[u8] = consts.unwrap_or(&[]);
+        if !consts.len().is_multiple_of(8) || consts.len() / 8 > 256 {
+            return Err(LoadError::InvalidConstants);
+        }
+        let memory = match memory {
+            Some(m) if m.len() == 4 => read_u32(m)?,
+            Some(_) => return Err(LoadError::InvalidMemorySize),
+            None => 0,
+        };
+        let stack = match stack {
+            Some(s) if s.len() == 4 => Some(read_u32(s)?),
+            Some(_) => return Err(LoadError::InvalidStackSize),
+            None => None,
+        };
+        // the entries of the read-only data must fill the segment exactly:
+        let rodata: &[u8] = rodata.unwrap_or(&[]);
+        let end = (RoEntries { rodata, offset: 0 }).last().map_or(0, |e| e.end());
+        if end != rodata.len() {
+            return Err(LoadError::InvalidRoData);
+        }
+        #[cfg(feature = "std")]
+        let debug = debug.map(DebugInfo::from_bytes).transpose()?;
+        #[cfg(not(feature = "std"))]
+        let _ = debug;
+        Ok(Pgm {
+            name: borrowed_str(name),
+            text: borrowed(text),
+            vars,
+            memory,
+            consts: borrowed(consts),
+            rodata: borrowed(rodata),
+            stack,
+            #[cfg(feature = "std")]
+            debug,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    /// A program that uses every section: constants, memory, read-only data, and debug info.
+    const SOURCE: &str = "
+        .bytes table: 1, 2, 3
+        memory 16
+        push 0x123456789
+        push_u8 1
+        load_ro table
+        add
+        out
+        fin
+    ";
+
+    fn program() -> Pgm<'static> {
+        let mut pgm = asm::assemble("sections", SOURCE, &[]).unwrap();
+        pgm.stack = Some(2);
+        pgm
+    }
+
+    /// Replaces the checksum at the end, so that only the content is checked.
+    fn fix_checksum(data: &mut [u8]) {
+        let len = data.len();
+        let crc = crc32(&data[..len - 4]);
+        data[len - 4..].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let pgm = program();
+        let data = pgm.to_bytes();
+        let read = Pgm::from_bytes(&data).unwrap();
+        assert_eq!(read.name, pgm.name);
+        assert_eq!(read.text, pgm.text);
+        assert_eq!(read.vars, pgm.vars);
+        assert_eq!(read.memory, 16);
+        assert_eq!(read.consts, pgm.consts);
+        assert_eq!(read.rodata, pgm.rodata);
+        assert_eq!(read.stack, Some(2));
+        assert_eq!(read.debug, pgm.debug);
+        assert_eq!(read.identity(), pgm.identity());
+        assert_eq!(read.to_bytes(), data);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let data = program().to_bytes();
+        for len in 0..data.len() {
+            assert!(Pgm::from_bytes(&data[..len]).is_err(), "accepted {} of {} bytes", len, data.len());
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted() {
+        let data = program().to_bytes();
+        for ix in 0..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[ix] ^= 0x10;
+            assert!(Pgm::from_bytes(&corrupted).is_err(), "accepted change at {}", ix);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sections() {
+        let data = program().to_bytes();
+        // the first section is the name; claim it is longer than the whole program:
+        let mut long = data.clone();
+        long[7..11].copy_from_slice(&u32::MAX.to_be_bytes());
+        fix_checksum(&mut long);
+        assert_eq!(Pgm::from_bytes(&long).unwrap_err(), LoadError::Truncated);
+        let mut unknown = data.clone();
+        unknown[6] = 0x7f;
+        fix_checksum(&mut unknown);
+        assert_eq!(Pgm::from_bytes(&unknown).unwrap_err(), LoadError::UnknownSection(0x7f));
+        let mut version = data;
+        version[4] = FORMAT_VERSION + 1;
+        fix_checksum(&mut version);
+        assert_eq!(Pgm::from_bytes(&version).unwrap_err(), LoadError::UnsupportedVersion(FORMAT_VERSION + 1));
+    }
 }
@@ -0,0 +1,318 @@
+//! Profiling, to find out where a program spends its instructions.
+//!
+//! `Profiler` is an `Observer` that counts the instructions the VM reports while its `trace`
+//! is active: per opcode, per position in bytecode, and per function reached with `CALL`.
+//! Counts add up over any number of runs.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::{op, Pgm};
+use crate::observer::{Forward, Observer, TraceEvent};
+
+/// Instructions counted for a function.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// Number of times the function was called.
+    pub calls: u64,
+    /// Instructions executed in the function itself.
+    pub own: u64,
+    /// Instructions executed in the function, including the functions it called.
+    pub total: u64,
+}
+
+/// A function call that is active while counting.
+struct ActiveCall {
+    /// Position of the function called.
+    function: usize,
+    /// Number of instructions counted before the call.
+    start: u64,
+}
+
+/// Observer that counts executed instructions.
+///
+/// The program's output still needs to be shown, so the profiler passes it on through
+/// `forward`; a program runs the same with and without profiling.
+pub struct Profiler<'o> {
+    /// Where output (and trace events, if wanted) go after counting.
+    pub forward: Forward<'o>,
+    /// Executed instructions, by opcode.
+    pub opcodes: [u64; 256],
+    /// Executed instructions, by position in bytecode.
+    pub pcs: Vec<u64>,
+    /// Functions called, by their position in bytecode.
+    ///
+    /// The `total` of a function is added when it returns; see `finish`.
+    pub functions: BTreeMap<usize, FunctionProfile>,
+    /// Calls currently active, innermost last.
+    active: Vec<ActiveCall>,
+    /// Number of calls currently active, by function.
+    nesting: BTreeMap<usize, usize>,
+    /// Number of instructions counted.
+    counted: u64,
+}
+
+impl<'o> Profiler<'o> {
+    /// Creates a profiler that passes the program's output on to another observer.
+    pub fn new(inner: Option<&'o mut dyn Observer>) -> Profiler<'o> {
+        Profiler {
+            forward: Forward::new(inner),
+            opcodes: [0; 256],
+            pcs: vec![],
+            functions: BTreeMap::new(),
+            active: vec![],
+            nesting: BTreeMap::new(),
+            counted: 0,
+        }
+    }
+
+    /// Total number of instructions counted.
+    pub fn total(&self) -> u64 {
+        self.pcs.iter().sum()
+    }
+
+    /// Records an executed instruction.
+    fn count(&mut self, pc: usize, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+        if self.pcs.len() <= pc {
+            self.pcs.resize(pc + 1, 0);
+        }
+        self.pcs[pc] += 1;
+        self.counted += 1;
+        if let Some(call) = self.active.last() {
+            self.functions.entry(call.function).or_default().own += 1;
+        }
+    }
+
+    /// Records a call of a function.
+    fn call(&mut self, function: usize) {
+        self.functions.entry(function).or_default().calls += 1;
+        *self.nesting.entry(function).or_default() += 1;
+        self.active.push(ActiveCall { function, start: self.counted });
+    }
+
+    /// Records the return from the innermost function.
+    fn ret(&mut self) {
+        let Some(call) = self.active.pop() else {
+            return;
+        };
+        let nesting = self.nesting.entry(call.function).or_default();
+        *nesting -= 1;
+        // the outermost call of a recursive function covers the inner ones:
+        if *nesting == 0 {
+            self.functions.entry(call.function).or_default().total += self.counted - call.start;
+        }
+    }
+
+    /// Ends all calls that are still active, adding their instructions to the functions' `total`.
+    ///
+    /// This happens when a program terminates, or when the next run starts. After a run that
+    /// ended with an error, call it before looking at the functions.
+    pub fn finish(&mut self) {
+        while !self.active.is_empty() {
+            self.ret();
+        }
+    }
+
+    /// Creates a text report of the `top` hottest source lines, functions, and opcodes.
+    ///
+    /// Positions are mapped to source lines with the program's debug information; if the
+    /// program's `source` is given, the lines are shown.
+    pub fn report(&self, pgm: &Pgm, source: Option<&str>, top: usize) -> String {
+        let total = self.total().max(1) as f64;
+        let debug = pgm.debug.as_ref();
+        let lines: Vec<&str> = source.map_or(vec![], |s| s.lines().collect());
+        let mut out = String::new();
+
+        // Lines: sum up the positions of each line (or use the positions without debug info).
+        let mut by_line: BTreeMap<usize, u64> = BTreeMap::new();
+        for (pc, &n) in self.pcs.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let line = debug.and_then(|d| d.line(pc)).unwrap_or(pc);
+            *by_line.entry(line).or_default() += n;
+        }
+        let mut hot: Vec<(usize, u64)> = by_line.into_iter().collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(out, "Hottest {}:", if debug.is_some() { "lines" } else { "positions" }).unwrap();
+        for &(line, n) in hot.iter().take(top) {
+            let text = line.checked_sub(1).and_then(|l| lines.get(l)).map_or("", |t| t.trim());
+            writeln!(out, "  {:>10} {:>6.2}%  {:>5}  {}", n, n as f64 * 100.0 / total, line, text).unwrap();
+        }
+
+        let mut functions: Vec<(&usize, &FunctionProfile)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        if !functions.is_empty() {
+            writeln!(out, "Hottest functions:").unwrap();
+            writeln!(out, "  {:>10} {:>10} {:>7} {:>8}  function", "total", "own", "total%", "calls").unwrap();
+        }
+        for (&pos, f) in functions.iter().take(top) {
+            let name = debug.and_then(|d| d.symbols.iter().find(|s| s.pos == pos))
+                .map_or_else(|| format!("pc={}", pos), |s| s.name.clone());
+            writeln!(out, "  {:>10} {:>10} {:>6.2}% {:>8}  {}",
+                     f.total, f.own, f.total as f64 * 100.0 / total, f.calls, name).unwrap();
+        }
+
+        let mut opcodes: Vec<(usize, u64)> = self.opcodes.iter().copied().enumerate().filter(|&(_, n)| n > 0).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        writeln!(out, "Hottest opcodes:").unwrap();
+        for &(opcode, n) in opcodes.iter().take(top) {
+            let mnemonic = op::info(opcode as u8).map_or("?", |i| i.mnemonic);
+            writeln!(out, "  {:>10} {:>6.2}%  {}", n, n as f64 * 100.0 / total, mnemonic).unwrap();
+        }
+        out
+    }
+}
+
+impl Observer for Profiler<'_> {
+    fn out(&mut self, value: i64, op_cnt: usize) {
+        self.forward.out(value, op_cnt);
+    }
+
+    fn trace(&mut self, event: &TraceEvent) {
+        match *event {
+            TraceEvent::Instruction { pc, opcode, op_cnt, .. } => {
+                if op_cnt == 1 {
+                    // a new run starts, a previous one might have ended by an error:
+                    self.finish();
+                }
+                self.count(pc, opcode)
+            },
+            TraceEvent::Call { dest, .. } => self.call(dest),
+            TraceEvent::Return { .. } => self.ret(),
+            TraceEvent::Terminated { .. } => self.finish(),
+            TraceEvent::Jump { .. } => {},
+        }
+        self.forward.trace(event);
+    }
+}
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::vm::{RuntimeError, VM};
+
+    /// Runs a program with a profiler attached.
+    fn profile(source: &str) -> (Pgm<'static>, Profiler<'static>, Result<(), RuntimeError>) {
+        let pgm = asm::assemble("profile", source, &[]).unwrap();
+        let mut profiler = Profiler::new(None);
+        let mut stack = [0; 32];
+        let mut vm = VM::new(&mut stack);
+        vm.trace = true;
+        vm.observer = Some(&mut profiler);
+        let result = vm.run(&pgm).map(|_| ()).map_err(|r| r.error);
+        (pgm, profiler, result)
+    }
+
+    /// Position of a label in a program.
+    fn label(pgm: &Pgm, name: &str) -> usize {
+        pgm.debug.as_ref().unwrap().label(name).unwrap()
+    }
+
+    #[test]
+    fn counts_instructions() {
+        let source = "
+            push_u8 3
+        loop:
+            push_u8 1
+            sub
+            dup
+            ifgt loop
+            out
+            fin
+        ";
+        let (pgm, profiler, result) = profile(source);
+        assert_eq!(result, Ok(()));
+        let at = label(&pgm, "loop");
+        assert_eq!((profiler.pcs[0], profiler.pcs[at]), (1, 3));
+        assert_eq!((profiler.opcodes[op::SUB as usize], profiler.opcodes[op::OUT as usize]), (3, 1));
+        assert_eq!(profiler.total(), 1 + 3 * 4 + 2);
+        assert!(profiler.functions.is_empty());
+    }
+
+    #[test]
+    fn counts_functions_with_and_without_callees() {
+        let source = "
+            push_u8 0
+            call f
+            push_u8 0
+            call f
+            fin
+        f:
+            push_u8 0
+            call g
+            ret
+        g:
+            ret
+        ";
+        let (pgm, profiler, _) = profile(source);
+        let f = &profiler.functions[&label(&pgm, "f")];
+        assert_eq!(f, &FunctionProfile { calls: 2, own: 6, total: 8 });
+        let g = &profiler.functions[&label(&pgm, "g")];
+        assert_eq!(g, &FunctionProfile { calls: 2, own: 2, total: 2 });
+    }
+
+    #[test]
+    fn counts_recursion_once() {
+        let source = "
+            push_u8 3
+            push_u8 1
+            call down
+            pop
+            fin
+        down:
+            local n
+            load_l n
+            ifle done
+            load_l n
+            push_u8 1
+            sub
+            push_u8 1
+            call down
+            pop
+        done:
+            ret
+        ";
+        let (pgm, profiler, _) = profile(source);
+        // nine instructions for 3, 2, and 1, three for 0:
+        let down = &profiler.functions[&label(&pgm, "down")];
+        assert_eq!(down, &FunctionProfile { calls: 4, own: 30, total: 30 });
+        assert_eq!(profiler.total(), 35);
+    }
+
+    #[test]
+    fn finish_counts_functions_left_by_error() {
+        let source = "
+            push_u8 0
+            call f
+            fin
+        f:
+            push_u8 1
+            push_u8 0
+            div
+            ret
+        ";
+        let (pgm, mut profiler, result) = profile(source);
+        assert_eq!(result, Err(RuntimeError::DivisionByZero));
+        let f = label(&pgm, "f");
+        assert_eq!(profiler.functions[&f].total, 0);
+        profiler.finish();
+        assert_eq!(profiler.functions[&f], FunctionProfile { calls: 1, own: 3, total: 3 });
+    }
+
+    #[test]
+    fn report() {
+        let source = "push_u8 0\ncall f\nfin\nf:\npush_u8 2\nf2:\npush_u8 1\nsub\ndup\nifgt f2\npop\nret\n";
+        let (pgm, profiler, _) = profile(source);
+        // `f` runs the loop twice, 11 of the 14 instructions are its own:
+        let expected = "\
+Hottest lines:
+           2  14.29%      7  push_u8 1
+           2  14.29%      8  sub
+Hottest functions:
+       total        own  total%    calls  function
+          11         11  78.57%        1  f
+Hottest opcodes:
+           4  28.57%  push_u8
+           2  14.29%  dup
+";
+        assert_eq!(profiler.report(&pgm, Some(source), 2), expected);
+    }
+}
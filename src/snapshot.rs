@@ -0,0 +1,205 @@
+//! Snapshots of the VM's execution state, to continue a program later or somewhere else.
+//!
+//! `VM::snapshot` captures everything a running program needs (stack, memory, registers).
+//! The snapshot can be encoded into bytes, stored or sent somewhere, read back with
+//! `Snapshot::from_bytes`, and handed to `VM::restore`, which continues exactly where the
+//! program stopped. A snapshot is bound to the program it was taken from.
+use core::fmt::{Display, Formatter};
+use crate::pgm::crc32;
+
+/// Magic number at the start of every encoded snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"LOVS";
+
+/// Version of the snapshot encoding written by `Snapshot::encode`.
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// Size of the encoded snapshot without stack and memory: magic, version, six u32, `op_cnt`, CRC.
+const FIXED_SIZE: usize = 4 + 1 + 4 * 6 + 8 + 4;
+
+/// An error when taking, encoding, reading or restoring a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    NotRunning,
+    BufferTooSmall,
+    Truncated,
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    InvalidChecksum,
+    ProgramMismatch,
+    StackTooSmall,
+    MemoryTooSmall,
+    InvalidState,
+    /// A frame on the stack is not one `CALL` could have created; holds its frame base.
+    InvalidFrame(usize),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SnapshotError {
+}
+
+/// Values on the stack, either borrowed from a VM or still encoded.
+#[derive(Debug, Clone, Copy)]
+enum Values<'s> {
+    /// Borrowed from the stack of a VM.
+    Native(&'s [i64]),
+    /// Borrowed from an encoded snapshot, 8 bytes each, big endian.
+    Encoded(&'s [u8]),
+}
+
+/// The execution state of a VM, at an instruction boundary.
+///
+/// Stack and memory are borrowed, either from the VM the snapshot was taken from, or from
+/// the bytes it was read from, so taking and reading a snapshot copies nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot<'s> {
+    /// Identity hash of the program (see `Pgm::identity`).
+    pub program: u32,
+    /// Program counter.
+    pub pc: usize,
+    /// Frame base.
+    pub fb: usize,
+    /// Number of instructions executed so far.
+    pub op_cnt: usize,
+    /// Maximal stack depth so far.
+    pub watermark: usize,
+    /// Values on the stack.
+    stack: Values<'s>,
+    /// The program's memory.
+    pub memory: &'s [u8],
+}
+
+/// Reads a big endian number of `N` bytes from the start of some bytes, and moves behind it.
+fn take<const N: usize>(data: &mut &[u8]) -> [u8; N] {
+    let mut b = [0; N];
+    b.copy_from_slice(&data[..N]);
+    *data = &data[N..];
+    b
+}
+
+impl<'s> Snapshot<'s> {
+    /// Creates a snapshot from the VM's state; used by `VM::snapshot`.
+    pub(crate) fn new(program: u32, pc: usize, fb: usize, op_cnt: usize, watermark: usize,
+                      stack: &'s [i64], memory: &'s [u8]) -> Snapshot<'s> {
+        Snapshot { program, pc, fb, op_cnt, watermark, stack: Values::Native(stack), memory }
+    }
+
+    /// Number of values on the stack.
+    pub fn depth(&self) -> usize {
+        match self.stack {
+            Values::Native(values) => values.len(),
+            Values::Encoded(bytes) => bytes.len() / 8,
+        }
+    }
+
+    /// Returns the values on the stack, bottom first.
+    pub fn stack(&self) -> impl Iterator<Item = i64> + 's {
+        let (native, encoded) = match self.stack {
+            Values::Native(values) => (values, &[][..]),
+            Values::Encoded(bytes) => (&[][..], bytes),
+        };
+        native.iter().copied().chain(encoded.chunks_exact(8).map(|b| {
+            i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+        }))
+    }
+
+    /// Returns a single value on the stack, counted from the bottom.
+    pub fn value(&self, ix: usize) -> Option<i64> {
+        match self.stack {
+            Values::Native(values) => values.get(ix).copied(),
+            Values::Encoded(bytes) => {
+                let b = bytes.get(ix * 8..ix * 8 + 8)?;
+                Some(i64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+            },
+        }
+    }
+
+    /// Number of bytes `encode` needs.
+    pub fn encoded_len(&self) -> usize {
+        FIXED_SIZE + self.depth() * 8 + self.memory.len()
+    }
+
+    /// Encodes the snapshot into a buffer and returns the number of bytes written.
+    ///
+    /// The encoding starts with `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION`, followed by the
+    /// program's identity, `pc`, `fb`, `watermark`, stack depth and memory size (u32 each),
+    /// `op_cnt` (u64), the stack values (i64 each), the memory, and the CRC-32 of everything
+    /// before. All numbers are big endian.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, SnapshotError> {
+        let len = self.encoded_len();
+        let buf = buf.get_mut(..len).ok_or(SnapshotError::BufferTooSmall)?;
+        let mut pos = 0;
+        let mut put = |bytes: &[u8]| {
+            buf[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+        };
+        put(&SNAPSHOT_MAGIC);
+        put(&[SNAPSHOT_VERSION]);
+        for v in [self.program as usize, self.pc, self.fb, self.watermark, self.depth(), self.memory.len()] {
+            put(&(v as u32).to_be_bytes());
+        }
+        put(&(self.op_cnt as u64).to_be_bytes());
+        for v in self.stack() {
+            put(&v.to_be_bytes());
+        }
+        put(self.memory);
+        let crc = crc32(&buf[..len - 4]);
+        buf[len - 4..].copy_from_slice(&crc.to_be_bytes());
+        Ok(len)
+    }
+
+    /// Encodes the snapshot into a new vector.
+    #[cfg(feature = "std")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.encoded_len()];
+        self.encode(&mut buf).unwrap();
+        buf
+    }
+
+    /// Reads a snapshot written by `encode`.
+    ///
+    /// The encoding is checked completely, but whether the snapshot fits a program is only
+    /// checked by `VM::restore`. Stack and memory are borrowed from `data`.
+    pub fn from_bytes(data: &'s [u8]) -> Result<Snapshot<'s>, SnapshotError> {
+        if data.len() < SNAPSHOT_MAGIC.len() || data[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        if data.len() < FIXED_SIZE {
+            return Err(SnapshotError::Truncated);
+        }
+        let (body, crc) = data.split_at(data.len() - 4);
+        if crc32(body) != u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(SnapshotError::InvalidChecksum);
+        }
+        if body[4] != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(body[4]));
+        }
+        let mut rest = &body[5..];
+        let mut u32s = [0; 6];
+        for v in &mut u32s {
+            *v = u32::from_be_bytes(take(&mut rest)) as usize;
+        }
+        let [program, pc, fb, watermark, depth, memory_len] = u32s;
+        let op_cnt = u64::from_be_bytes(take(&mut rest)) as usize;
+        // what is left must be exactly the stack and the memory:
+        let stack_len = depth.checked_mul(8).ok_or(SnapshotError::Truncated)?;
+        if stack_len.checked_add(memory_len) != Some(rest.len()) {
+            return Err(SnapshotError::Truncated);
+        }
+        let (stack, memory) = rest.split_at(stack_len);
+        Ok(Snapshot {
+            program: program as u32,
+            pc,
+            fb,
+            op_cnt,
+            watermark,
+            stack: Values::Encoded(stack),
+            memory,
+        })
+    }
+}
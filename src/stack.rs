@@ -0,0 +1,340 @@
+//! Static analysis of the stack depth a program needs.
+//!
+//! [`max_depth`] follows every path through the bytecode without executing it and finds the
+//! maximal number of stack cells a program can use, including the frames of function calls.
+//! With that, a VM's stack can be made exactly as large as needed, instead of finding out
+//! on the device with a `StackOverflow`.
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{Display, Formatter};
+use crate::{op, Pgm};
+use crate::op::{Flow, Oparg};
+
+/// Number of stack cells `CALL` uses for a frame, besides the parameters.
+pub const FRAME_SIZE: usize = 3;
+
+/// Result of the stack depth analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackDepth {
+    /// The program never uses more stack cells than this (global variables included).
+    Bounded(usize),
+    /// The program uses recursion, its stack depth depends on the data.
+    Unbounded {
+        /// Position of a function that calls itself, directly or indirectly.
+        function: usize,
+    },
+}
+
+impl Display for StackDepth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackDepth::Bounded(depth) => write!(f, "{}", depth),
+            StackDepth::Unbounded { .. } => write!(f, "unbounded"),
+        }
+    }
+}
+
+/// A problem that prevents the analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackError {
+    /// The instruction pops more values than there are on the stack.
+    StackUnderflow,
+    /// Two paths reach the instruction with different stack depths.
+    InconsistentDepth { expected: usize, found: usize },
+    /// `RET` outside of a function, or with a different number of values than were passed.
+    InvalidReturn,
+    /// The number of values passed by `CALL` is not a constant.
+    UnknownArgumentCount,
+    /// `SYSCALL` uses a native function that is unknown.
+    UnknownSyscall(u8),
+    /// The bytecode cannot be decoded here; `verify::verify` tells more.
+    InvalidCode,
+}
+
+impl Display for StackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for StackError {
+}
+
+/// Report of a failed stack depth analysis.
+#[derive(Debug)]
+pub struct StackErrorReport {
+    /// Name of the program that was analysed.
+    pub name: String,
+    /// Position in bytecode of the instruction with the problem.
+    pub pos: usize,
+    /// What is wrong.
+    pub error: StackError,
+}
+
+impl Display for StackErrorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stack analysis of program '{}' failed at {}: {}", self.name, self.pos, self.error)
+    }
+}
+
+impl error::Error for StackErrorReport {
+}
+
+/// What we know about the stack when reaching an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    /// Number of values above the frame base.
+    depth: usize,
+    /// The value on top of the stack, if it is a known constant.
+    top: Option<i64>,
+}
+
+/// State of an analysis run over a whole program.
+struct Analysis<'p, 's> {
+    pgm: &'p Pgm<'p>,
+    /// Tells how many values a native function pops and pushes, by id.
+    syscalls: &'s dyn Fn(u8) -> Option<(usize, usize)>,
+    /// Maximal depth of functions already analysed, by position and number of parameters.
+    functions: HashMap<(usize, usize), usize>,
+    /// Functions being analysed right now, the innermost last.
+    active: Vec<usize>,
+    /// A function found to be recursive.
+    recursive: Option<usize>,
+}
+
+impl Analysis<'_, '_> {
+    /// Creates a report for a problem at a position.
+    fn error(&self, pos: usize, error: StackError) -> StackErrorReport {
+        StackErrorReport { name: self.pgm.name.to_string(), pos, error }
+    }
+
+    /// Returns the maximal depth above the frame base a piece of code reaches.
+    ///
+    /// The code starts at `entry` with `start` values above the frame base. Functions
+    /// (`params` is `Some`) must return with the number of values they got; the main
+    /// program (`None`) cannot return at all.
+    fn code(&mut self, entry: usize, start: usize, params: Option<usize>) -> Result<usize, StackErrorReport> {
+        let text = &self.pgm.text;
+        let mut states: HashMap<usize, State> = HashMap::new();
+        states.insert(entry, State { depth: start, top: None });
+        let mut todo = vec![entry];
+        let mut max = start;
+        while let Some(pos) = todo.pop() {
+            let State { depth, top } = states[&pos];
+            let info = text.get(pos).and_then(|&o| op::info(o))
+                .ok_or_else(|| self.error(pos, StackError::InvalidCode))?;
+            let size = 1 + info.oparg.size();
+            let oparg = text.get(pos + 1..pos + size).ok_or_else(|| self.error(pos, StackError::InvalidCode))?;
+            let jump = || pos as i64 + size as i64 + i16::from_be_bytes([oparg[0], oparg[1]]) as i64;
+            // the depth after the instruction, and where execution continues:
+            let (next, successors) = match info.flow {
+                Flow::Call => {
+                    // the number of parameters is popped, the parameters stay for the function:
+                    let n = match top {
+                        Some(n) if n >= 0 => n as usize,
+                        _ => return Err(self.error(pos, StackError::UnknownArgumentCount)),
+                    };
+                    if depth < n + 1 {
+                        return Err(self.error(pos, StackError::StackUnderflow));
+                    }
+                    let dest = usize::try_from(jump()).map_err(|_| self.error(pos, StackError::InvalidCode))?;
+                    let inner = self.function(dest, n)?;
+                    // the frame goes below the parameters, which are counted in `inner`:
+                    max = max.max(depth - 1 - n + FRAME_SIZE + inner);
+                    (State { depth: depth - 1, top: None }, vec![pos + size])
+                },
+                Flow::Return => {
+                    if params != Some(depth) {
+                        return Err(self.error(pos, StackError::InvalidReturn));
+                    }
+                    continue;
+                },
+                Flow::Fin => continue,
+                _ => {
+                    let (pops, pushes) = if info.opcode == op::SYSCALL {
+                        (self.syscalls)(oparg[0]).ok_or_else(|| self.error(pos, StackError::UnknownSyscall(oparg[0])))?
+                    } else {
+                        (info.pops as usize, info.pushes as usize)
+                    };
+                    if depth < pops {
+                        return Err(self.error(pos, StackError::StackUnderflow));
+                    }
+                    // remember constants, so that we know how many parameters `CALL` passes:
+                    let top = match info.oparg {
+                        _ if info.opcode == op::DUP => top,
+                        Oparg::U8 => Some(oparg[0] as i64),
+                        Oparg::I8 => Some(oparg[0] as i8 as i64),
+                        Oparg::I16 => Some(i16::from_be_bytes([oparg[0], oparg[1]]) as i64),
+                        Oparg::I32 => Some(i32::from_be_bytes([oparg[0], oparg[1], oparg[2], oparg[3]]) as i64),
+                        Oparg::Const => self.pgm.constant(oparg[0]),
+                        _ => None,
+                    };
+                    let next = State { depth: depth - pops + pushes, top };
+                    let successors = match info.flow {
+                        Flow::Goto => vec![jump()],
+                        Flow::Branch => vec![pos as i64 + size as i64, jump()],
+                        _ => vec![pos as i64 + size as i64],
+                    };
+                    let successors = successors.into_iter()
+                        .map(|p| usize::try_from(p).map_err(|_| self.error(pos, StackError::InvalidCode)))
+                        .collect::<Result<Vec<usize>, _>>()?;
+                    (next, successors)
+                },
+            };
+            max = max.max(next.depth);
+            for succ in successors {
+                match states.get_mut(&succ) {
+                    None => {
+                        states.insert(succ, next);
+                        todo.push(succ);
+                    },
+                    Some(known) if known.depth != next.depth => {
+                        return Err(self.error(succ, StackError::InconsistentDepth { expected: known.depth, found: next.depth }));
+                    },
+                    Some(known) => if known.top.is_some() && known.top != next.top {
+                        // different constants meet, we no longer know the value:
+                        known.top = None;
+                        todo.push(succ);
+                    },
+                }
+            }
+        }
+        Ok(max)
+    }
+
+    /// Returns the maximal depth above its frame base of a function called with `n` parameters.
+    fn function(&mut self, pos: usize, n: usize) -> Result<usize, StackErrorReport> {
+        if let Some(&max) = self.functions.get(&(pos, n)) {
+            return Ok(max);
+        }
+        if self.active.contains(&pos) {
+            // recursion: the depth depends on the data, we do not count it again
+            self.recursive.get_or_insert(pos);
+            return Ok(n);
+        }
+        self.active.push(pos);
+        let max = self.code(pos, n, Some(n));
+        self.active.pop();
+        let max = max?;
+        self.functions.insert((pos, n), max);
+        Ok(max)
+    }
+}
+
+/// Computes the maximal number of stack cells a program can use.
+///
+/// All paths through the program are followed, into every function called, with the frame
+/// `CALL` creates (`FRAME_SIZE` cells plus the parameters). `syscalls` tells how many values
+/// each native function pops and pushes. The analysis fails, if paths meet with different
+/// stack depths, if the stack could underflow, if a function returns a different number of
+/// values than it got, or if the number of parameters passed by `CALL` is not a constant
+/// pushed before. Programs with recursion are analysed completely, but their depth is
+/// `Unbounded`.
+pub fn max_depth(pgm: &Pgm, syscalls: &dyn Fn(u8) -> Option<(usize, usize)>) -> Result<StackDepth, StackErrorReport> {
+    let mut analysis = Analysis {
+        pgm,
+        syscalls,
+        functions: HashMap::new(),
+        active: vec![],
+        recursive: None,
+    };
+    // the global variables are at the bottom of the stack:
+    let max = analysis.code(0, pgm.vars as usize, None)?;
+    Ok(match analysis.recursive {
+        Some(function) => StackDepth::Unbounded { function },
+        None => StackDepth::Bounded(max),
+    })
+}
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::fixture::{self, arities};
+    use crate::vm::{RuntimeError, Status};
+
+    #[test]
+    fn bound_matches_watermark() {
+        let mut checked = 0;
+        for (name, source) in fixture::examples() {
+            let Ok(pgm) = asm::assemble(&name, &source, &fixture::syscall_names()) else {
+                continue;
+            };
+            let Ok(StackDepth::Bounded(bound)) = max_depth(&pgm, &arities) else {
+                continue;
+            };
+            let ran = fixture::run(&pgm, bound, |_| {});
+            assert!(ran.watermark <= bound, "{}", name);
+            // programs that fail might stop before they reach the bound:
+            if ran.result == Ok(Status::Finished) {
+                assert_eq!(ran.watermark, bound, "{}", name);
+                // and with a single cell less, they do not fit:
+                let ran = fixture::run(&pgm, bound - 1, |_| {});
+                assert_eq!(ran.result, Err(RuntimeError::StackOverflow), "{}", name);
+                checked += 1;
+            }
+        }
+        assert!(checked > 5);
+    }
+
+    #[test]
+    fn function_frames_are_counted() {
+        let source = "
+            push_u8 5
+            push_u8 1
+            call twice
+            out
+            fin
+        twice:
+            local x
+            load_l x
+            dup
+            add
+            store_l x
+            ret
+        ";
+        let pgm = asm::assemble("call", source, &[]).unwrap();
+        // the parameter, the frame, and two values in the function:
+        assert_eq!(max_depth(&pgm, &arities).unwrap(), StackDepth::Bounded(1 + FRAME_SIZE + 2));
+    }
+
+    #[test]
+    fn recursion_is_unbounded() {
+        let source = "
+            push_u8 3
+            push_u8 1
+            call down
+            pop
+            fin
+        down:
+            local n
+            load_l n
+            ifle done
+            load_l n
+            push_u8 1
+            sub
+            push_u8 1
+            call down
+            pop
+        done:
+            ret
+        ";
+        let pgm = asm::assemble("recursion", source, &[]).unwrap();
+        let down = pgm.debug.as_ref().unwrap().label("down").unwrap();
+        assert_eq!(max_depth(&pgm, &arities).unwrap(), StackDepth::Unbounded { function: down });
+    }
+
+    #[test]
+    fn rejects_inconsistent_depth() {
+        let source = "
+            push_u8 0
+            ifeq skip
+            push_u8 1
+        skip:
+            fin
+        ";
+        let pgm = asm::assemble("inconsistent", source, &[]).unwrap();
+        let report = max_depth(&pgm, &arities).unwrap_err();
+        assert_eq!(report.error, StackError::InconsistentDepth { expected: 0, found: 1 });
+    }
+}
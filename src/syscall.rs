@@ -0,0 +1,90 @@
+//! Native functions the host provides to programs running in the VM.
+//!
+//! A program calls a native function with `op::SYSCALL`, giving the function's id as oparg.
+//! The host puts its functions into the VM's `SyscallTable` before running a program.
+use core::fmt::{Debug, Formatter};
+use crate::vm::RuntimeError;
+
+/// Maximal number of values a native function can pop or push.
+pub const MAX_SYSCALL_VALUES: usize = 8;
+
+/// A native function, implemented by the host, that programs can call.
+///
+/// Every function declares how many values it pops from the stack (its arguments) and
+/// how many it pushes (its results), so that the VM can check the stack before calling it.
+pub trait Syscall {
+    /// Number of values the function pops from the stack.
+    fn pops(&self) -> usize;
+    /// Number of values the function pushes to the stack.
+    fn pushes(&self) -> usize;
+    /// Executes the function.
+    ///
+    /// `args` holds the popped values in the order they were pushed. `results` has room
+    /// for exactly the declared number of values, they are pushed in that order.
+    fn call(&mut self, args: &[i64], results: &mut [i64]) -> Result<(), RuntimeError>;
+}
+
+/// Wraps a closure so that it can be used as a `Syscall`.
+pub struct NativeFn<F> {
+    pops: usize,
+    pushes: usize,
+    f: F,
+}
+
+impl<F> NativeFn<F>
+    where F: FnMut(&[i64], &mut [i64]) -> Result<(), RuntimeError> {
+    /// Creates a native function from a closure with a declared stack effect.
+    pub fn new(pops: usize, pushes: usize, f: F) -> NativeFn<F> {
+        NativeFn { pops, pushes, f }
+    }
+}
+
+impl<F> Syscall for NativeFn<F>
+    where F: FnMut(&[i64], &mut [i64]) -> Result<(), RuntimeError> {
+    fn pops(&self) -> usize {
+        self.pops
+    }
+
+    fn pushes(&self) -> usize {
+        self.pushes
+    }
+
+    fn call(&mut self, args: &[i64], results: &mut [i64]) -> Result<(), RuntimeError> {
+        (self.f)(args, results)
+    }
+}
+
+/// Registry of the native functions available to programs.
+///
+/// Memory for the table is provided by the caller; a function's id is its index.
+#[derive(Default)]
+pub struct SyscallTable<'a> {
+    entries: &'a mut [Option<&'a mut dyn Syscall>],
+}
+
+impl<'a> SyscallTable<'a> {
+    /// Creates a table from a slice of native functions, indexed by id.
+    ///
+    /// Functions that pop or push more than `MAX_SYSCALL_VALUES` values will fail when
+    /// they are called.
+    pub fn new(entries: &'a mut [Option<&'a mut dyn Syscall>]) -> SyscallTable<'a> {
+        SyscallTable { entries }
+    }
+
+    /// Gets the native function registered for an id.
+    pub fn get_mut(&mut self, id: u8) -> Option<&mut (dyn Syscall + 'a)> {
+        match self.entries.get_mut(id as usize) {
+            Some(Some(s)) => Some(&mut **s),
+            _ => None,
+        }
+    }
+}
+
+impl Debug for SyscallTable<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        // closures cannot be printed, so we list the registered ids:
+        f.debug_set()
+            .entries(self.entries.iter().enumerate().filter(|(_, s)| s.is_some()).map(|(id, _)| id))
+            .finish()
+    }
+}
@@ -0,0 +1,337 @@
+//! Static verification of bytecode, before it is executed in the VM.
+//!
+//! The VM itself only notices malformed bytecode when it runs into it. Programs that are
+//! loaded from outside sources should be checked with [`verify`] first, so that an invalid
+//! program is rejected before a single instruction is executed.
+//!
+//! Verification does not allocate. The memory it needs is provided by the caller with
+//! [`verify_with`], like the VM's stack is; with `std`, [`verify`] provides it.
+use core::fmt::{Display, Formatter};
+use crate::{op, Pgm};
+use crate::op::{Flow, OpInfo, Oparg};
+
+/// A problem found in bytecode during verification.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyError {
+    /// The byte at this position is not a known opcode.
+    UnknownOpcode(u8),
+    /// The instruction's oparg reaches past the end of the program.
+    TruncatedOparg,
+    /// Jump destination is outside the program or not at the start of an instruction.
+    InvalidJump(i64),
+    /// `LOAD` or `STORE` use a global variable the program does not have.
+    InvalidVariable(u8),
+    /// `PUSH_C` uses a constant the program's constant pool does not have.
+    InvalidConstant(u8),
+    /// `LOAD_RO` does not point to the start of an entry in the read-only data segment.
+    InvalidRoData(u16),
+    /// Execution can run past the end of the program without reaching `FIN`.
+    MissingFin,
+    /// The memory given for verification is smaller than the program's bytecode.
+    TooLarge,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyError {
+}
+
+/// A single problem, together with the position in the bytecode it was found at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VerifyIssue {
+    /// Byte offset of the start of the offending instruction.
+    pub pos: usize,
+    /// What is wrong with it.
+    pub error: VerifyError,
+}
+
+/// Maximal number of issues recorded in a `VerifyReport`.
+pub const MAX_ISSUES: usize = 4;
+
+/// Report of a failed verification.
+///
+/// Lists the issues that were found in the program, ordered by position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyReport {
+    /// Number of issues found, including those that were not recorded.
+    pub count: usize,
+    /// The first issues found, ordered by their position in the bytecode.
+    issues: [VerifyIssue; MAX_ISSUES],
+    /// Number of valid entries in `issues`.
+    issues_len: u8,
+}
+
+impl VerifyReport {
+    /// Creates a report without any issues.
+    fn new() -> VerifyReport {
+        let none = VerifyIssue { pos: 0, error: VerifyError::MissingFin };
+        VerifyReport { count: 0, issues: [none; MAX_ISSUES], issues_len: 0 }
+    }
+
+    /// Issues found in the program, ordered by their position in the bytecode.
+    ///
+    /// Only the `MAX_ISSUES` issues at the lowest positions are recorded.
+    pub fn issues(&self) -> &[VerifyIssue] {
+        &self.issues[..self.issues_len as usize]
+    }
+
+    /// Records an issue in order, dropping the last one if there is no room left.
+    fn add(&mut self, pos: usize, error: VerifyError) {
+        self.count += 1;
+        let len = self.issues_len as usize;
+        let ix = self.issues[..len].partition_point(|i| i.pos <= pos);
+        if ix == MAX_ISSUES {
+            return;
+        }
+        let end = (len + 1).min(MAX_ISSUES);
+        self.issues.copy_within(ix..end - 1, ix + 1);
+        self.issues[ix] = VerifyIssue { pos, error };
+        self.issues_len = end as u8;
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "invalid bytecode:")?;
+        for issue in self.issues() {
+            write!(f, "\n  at {}: {}", issue.pos, issue.error)?;
+        }
+        if self.count > self.issues().len() {
+            write!(f, "\n  and {} more", self.count - self.issues().len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyReport {
+}
+
+/// A single decoded instruction.
+pub(crate) struct Decoded {
+    /// Start of instruction in bytecode.
+    pub pos: usize,
+    /// Description of the instruction.
+    pub info: &'static OpInfo,
+    /// Number of bytes the instruction takes, including the opcode.
+    pub size: usize,
+    /// Absolute jump destination, for instructions that jump.
+    pub dest: Option<i64>,
+}
+
+/// Decodes the instruction at a position, if it is known and complete.
+fn decode(text: &[u8], pos: usize) -> Option<Decoded> {
+    let info = op::info(*text.get(pos)?)?;
+    let size = 1 + info.oparg.size();
+    if pos + size > text.len() {
+        return None;
+    }
+    let dest = if info.oparg == Oparg::Jump {
+        let delta = i16::from_be_bytes([text[pos + 1], text[pos + 2]]);
+        Some((pos + size) as i64 + delta as i64)
+    } else {
+        None
+    };
+    Some(Decoded { pos, info, size, dest })
+}
+
+/// Decodes the instructions from the start, up to the end or the first one that cannot be decoded.
+pub(crate) fn instructions(text: &[u8]) -> impl Iterator<Item = Decoded> + '_ {
+    let mut pos = 0;
+    core::iter::from_fn(move || {
+        let d = decode(text, pos)?;
+        pos += d.size;
+        Some(d)
+    })
+}
+
+/// Marks an instruction starts at this position.
+const START: u8 = 1;
+/// Marks an instruction execution can reach.
+const REACHED: u8 = 2;
+/// Marks a reached instruction whose successors have been marked as reached.
+const DONE: u8 = 4;
+
+/// Gives the position of an instruction at `dest`, if there is one.
+fn instruction_at(marks: &[u8], dest: i64) -> Option<usize> {
+    let pos = usize::try_from(dest).ok()?;
+    marks.get(pos).filter(|&&m| m & START != 0).map(|_| pos)
+}
+
+/// Checks a program's bytecode without executing it.
+///
+/// The complete `text` is decoded. Verification fails on unknown opcodes, on truncated opargs,
+/// on jumps that do not land on the start of an instruction, on `LOAD`/`STORE` using variables
+/// the program does not declare, on `PUSH_C` using constants the program does not have, on
+/// `LOAD_RO` not using an entry of the read-only data, and on code paths that can run past
+/// the end without `FIN`.
+#[cfg(feature = "std")]
+pub fn verify(pgm: &Pgm) -> Result<(), VerifyReport> {
+    verify_with(pgm, &mut vec![0; pgm.text.len()])
+}
+
+/// Checks a program's bytecode without executing it, like [`verify`].
+///
+/// `marks` is used as working memory; it needs one byte for every byte of `text`, or
+/// verification fails with `TooLarge`.
+pub fn verify_with(pgm: &Pgm, marks: &mut [u8]) -> Result<(), VerifyReport> {
+    let text = &pgm.text;
+    let mut report = VerifyReport::new();
+    let Some(marks) = marks.get_mut(..text.len()) else {
+        report.add(0, VerifyError::TooLarge);
+        return Err(report);
+    };
+    marks.fill(0);
+    // First run: decode linearly, marking where instructions start.
+    let mut end = 0;
+    for d in instructions(text) {
+        marks[d.pos] = START;
+        end = d.pos + d.size;
+    }
+    if end < text.len() {
+        // we cannot know how to continue decoding after this:
+        let opcode = text[end];
+        let error = if op::info(opcode).is_some() { VerifyError::TruncatedOparg } else { VerifyError::UnknownOpcode(opcode) };
+        report.add(end, error);
+    }
+
+    // Second run: check the opargs of all instructions we could decode.
+    for d in instructions(text) {
+        if let Some(dest) = d.dest {
+            if instruction_at(marks, dest).is_none() {
+                report.add(d.pos, VerifyError::InvalidJump(dest));
+            }
+        }
+        if d.info.oparg == Oparg::Global {
+            let idx = text[d.pos + 1];
+            if idx >= pgm.vars {
+                report.add(d.pos, VerifyError::InvalidVariable(idx));
+            }
+        }
+        if d.info.oparg == Oparg::RoData {
+            let offset = u16::from_be_bytes([text[d.pos + 1], text[d.pos + 2]]);
+            if !pgm.rodata_entries().any(|e| e.offset == offset as usize) {
+                report.add(d.pos, VerifyError::InvalidRoData(offset));
+            }
+        }
+        if d.info.oparg == Oparg::Const {
+            let idx = text[d.pos + 1];
+            if idx as usize >= pgm.const_count() {
+                report.add(d.pos, VerifyError::InvalidConstant(idx));
+            }
+        }
+    }
+
+    // Third run: follow all code paths from the start and look for ways to run off the end.
+    // Every pass handles the instructions reached so far; only jumps backwards need another.
+    if text.is_empty() {
+        report.add(0, VerifyError::MissingFin);
+    } else if marks[0] & START != 0 {
+        marks[0] |= REACHED;
+    }
+    let mut again = true;
+    while again {
+        again = false;
+        for d in instructions(text) {
+            if marks[d.pos] & (REACHED | DONE) != REACHED {
+                continue;
+            }
+            marks[d.pos] |= DONE;
+            // invalid jumps have been reported above already
+            let jump = d.dest.and_then(|dest| instruction_at(marks, dest));
+            let next = d.pos + d.size;
+            let mut follows = None;
+            if !matches!(d.info.flow, Flow::Goto | Flow::Return | Flow::Fin) {
+                if next == text.len() {
+                    report.add(d.pos, VerifyError::MissingFin);
+                }
+                // next instruction could not be decoded, if it is none; that has been reported already:
+                follows = instruction_at(marks, next as i64);
+            }
+            for target in [jump, follows].into_iter().flatten() {
+                if marks[target] & REACHED == 0 {
+                    marks[target] |= REACHED;
+                    again |= target < d.pos;
+                }
+            }
+        }
+    }
+
+    if report.count == 0 {
+        Ok(())
+    } else {
+        Err(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::program;
+
+    /// Verifies bytecode and returns the issues found.
+    fn issues(text: &[u8]) -> Vec<VerifyIssue> {
+        verify(&program(text)).err().map_or(vec![], |r| r.issues().to_vec())
+    }
+
+    #[test]
+    fn accepts_valid_jumps() {
+        // a jump to the last instruction, and one back to the start:
+        assert_eq!(issues(&[op::PUSH_U8, 1, op::IFEQ, 0, 3, op::GOTO, 0xff, 0xf8, op::FIN]), vec![]);
+    }
+
+    #[test]
+    fn rejects_jump_into_instruction() {
+        // `goto` at 2 jumps to 1, which is the oparg of `push_u8`:
+        let found = issues(&[op::PUSH_U8, 5, op::GOTO, 0xff, 0xfc, op::FIN]);
+        assert_eq!(found, vec![VerifyIssue { pos: 2, error: VerifyError::InvalidJump(1) }]);
+    }
+
+    #[test]
+    fn rejects_jump_past_end() {
+        let found = issues(&[op::GOTO, 0, 5, op::FIN]);
+        assert_eq!(found, vec![VerifyIssue { pos: 0, error: VerifyError::InvalidJump(8) }]);
+        // the end itself is no instruction either:
+        let found = issues(&[op::PUSH_U8, 0, op::IFEQ, 0, 1, op::FIN]);
+        assert_eq!(found, vec![VerifyIssue { pos: 2, error: VerifyError::InvalidJump(6) }]);
+    }
+
+    #[test]
+    fn rejects_running_off_the_end() {
+        let found = issues(&[op::PUSH_U8, 5, op::OUT]);
+        assert_eq!(found.iter().map(|i| &i.error).collect::<Vec<_>>(), vec![&VerifyError::MissingFin]);
+    }
+
+    #[test]
+    fn follows_jumps_backwards() {
+        // `out` at 14 is only reached from `push_u8 1` at 3, which a later `ifeq` jumps back to:
+        let text = [op::GOTO, 0, 5, op::PUSH_U8, 1, op::GOTO, 0, 6, op::PUSH_U8, 0, op::IFEQ, 0xff, 0xf6, op::FIN, op::OUT];
+        assert_eq!(issues(&text), vec![VerifyIssue { pos: 14, error: VerifyError::MissingFin }]);
+    }
+
+    #[test]
+    fn records_the_first_issues() {
+        // every `goto` jumps into its own oparg:
+        let mut text = [op::GOTO, 0xff, 0xfe].repeat(6);
+        text.push(op::FIN);
+        let report = verify(&program(&text)).unwrap_err();
+        assert_eq!(report.count, 6);
+        let positions: Vec<usize> = report.issues().iter().map(|i| i.pos).collect();
+        assert_eq!(positions, vec![0, 3, 6, 9]);
+        assert!(report.to_string().ends_with("\n  and 2 more"));
+    }
+
+    #[test]
+    fn verifies_in_given_memory() {
+        let pgm = program(&[op::PUSH_U8, 1, op::OUT, op::FIN]);
+        // the memory does not need to be cleared:
+        assert_eq!(verify_with(&pgm, &mut [0xff; 8]), Ok(()));
+        let report = verify_with(&pgm, &mut [0; 3]).unwrap_err();
+        assert_eq!(report.issues(), &[VerifyIssue { pos: 0, error: VerifyError::TooLarge }]);
+    }
+}
@@ -1,8 +1,9 @@
-use std::cmp::max;
-use std::error;
-use std::fmt::{Display, Formatter};
-use moveslice::Moveslice;
+use core::cmp::max;
+use core::fmt::{Display, Formatter};
 use crate::{op, Pgm};
+use crate::syscall::{SyscallTable, MAX_SYSCALL_VALUES};
+use crate::observer::{Observer, TraceEvent};
+use crate::snapshot::{Snapshot, SnapshotError};
 
 /// An error that happens during execution of a program inside the VM.
 #[derive(Debug, Clone, PartialEq)]
@@ -16,28 +17,206 @@ pub enum RuntimeError {
     InstructionLimitExceeded,
     InvalidVariable,
     InvalidReturn,
+    UnknownSyscall(u8),
+    InvalidSyscall(u8),
+    ArithmeticOverflow,
+    InvalidConstant(u8),
+    MemoryFault,
+    InvalidRoData,
 }
 
 impl Display for RuntimeError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-impl error::Error for RuntimeError {
+#[cfg(feature = "std")]
+impl std::error::Error for RuntimeError {
+}
+
+/// Maximal number of return addresses recorded in the backtrace of a `RuntimeErrorReport`.
+pub const MAX_BACKTRACE: usize = 16;
+
+/// Report of a runtime error, with the context it happened in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeErrorReport {
+    /// Error that occurred.
+    pub error: RuntimeError,
+    /// Start of the instruction that failed.
+    pub pos: usize,
+    /// Opcode of the instruction that failed, if it could be fetched.
+    pub opcode: Option<u8>,
+    /// Number of instructions executed, including the one that failed.
+    pub op_cnt: usize,
+    /// Number of values on the stack.
+    pub depth: usize,
+    /// Return addresses of the active calls, innermost first.
+    backtrace: [u32; MAX_BACKTRACE],
+    /// Number of valid entries in `backtrace`.
+    backtrace_len: u8,
+}
+
+impl RuntimeErrorReport {
+    /// Return addresses of the functions active when the error occurred, innermost first.
+    ///
+    /// Each return address points behind the `CALL` instruction that called the function.
+    /// Only the innermost `MAX_BACKTRACE` calls are recorded.
+    pub fn backtrace(&self) -> &[u32] {
+        &self.backtrace[..self.backtrace_len as usize]
+    }
+}
+
+impl Display for RuntimeErrorReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} at pc={}", self.error, self.pos)?;
+        if let Some(opcode) = self.opcode {
+            let mnemonic = op::info(opcode).map_or("?", |i| i.mnemonic);
+            write!(f, " (op 0x{:02x} {})", opcode, mnemonic)?;
+        }
+        write!(f, ", op_cnt={}, stack-depth={}", self.op_cnt, self.depth)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RuntimeErrorReport {
+}
+
+/// How arithmetic instructions handle results that do not fit into an `i64`.
+///
+/// The behaviour is the same in every build profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Results wrap around (two's complement).
+    #[default]
+    Wrapping,
+    /// Results are clamped to `i64::MIN` or `i64::MAX`.
+    Saturating,
+    /// Execution fails with `RuntimeError::ArithmeticOverflow`.
+    Trapping,
+}
+
+/// Maximal number of breakpoints a VM can hold.
+pub const MAX_BREAKPOINTS: usize = 16;
+
+/// Maximal number of watchpoints a VM can hold.
+pub const MAX_WATCHPOINTS: usize = 8;
+
+/// A variable watched for writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// Global variable by index, written by `STORE`.
+    Global(u8),
+    /// Local variable by index, written by `STORE_L` or `SWAP_L` in any frame.
+    Local(u8),
+}
+
+/// Why execution stopped before the program finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The instruction at this position is a breakpoint; it has not been executed, yet.
+    Breakpoint(usize),
+    /// A watched variable was written by the instruction executed last (at `op_pos`).
+    Watchpoint(Watch),
+}
+
+/// An error when setting a breakpoint or watchpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakpointError {
+    TooMany,
+    NoDebugInfo,
+    NoCodeAtLine(usize),
+}
+
+impl Display for BreakpointError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BreakpointError {
+}
+
+/// A function call that is active, as found on the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Frame base of the called function (position of its first local variable).
+    pub fb: usize,
+    /// Return address, pointing behind the `CALL` instruction that called the function.
+    pub ret: usize,
+}
+
+/// Iterator over the frames `CALL` put on the stack, innermost first.
+#[derive(Debug, Clone)]
+pub struct Frames<'s> {
+    /// The values on the stack.
+    stack: &'s [i64],
+    /// The lowest possible frame base.
+    min: usize,
+    /// Frame base of the next frame.
+    fb: usize,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        let fb = self.fb;
+        if fb < self.min || fb > self.stack.len() {
+            return None;
+        }
+        let ret = self.stack[fb - 2];
+        let caller_fb = self.stack[fb - 1];
+        if ret < 0 || ret > u32::MAX as i64 || caller_fb < 0 || caller_fb as usize >= fb {
+            // this is no valid frame, stop here
+            self.fb = 0;
+            return None;
+        }
+        self.fb = caller_fb as usize;
+        Some(Frame { fb, ret: ret as usize })
+    }
+}
+
+/// Execution status of the VM, as reported by `step` and `run_for`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// The program can continue executing.
+    Running,
+    /// Execution stopped at a breakpoint or watchpoint; the program can continue executing.
+    Stopped(Stop),
+    /// The program terminated by executing `FIN` (or no program was started, yet).
+    Finished,
+    /// Execution stopped because of a runtime error.
+    Faulted(RuntimeErrorReport),
 }
 
 /// The virtual machine itself.
 ///
 /// Holds the state during execution of programs.
 #[derive(Debug)]
-pub struct VM {
+pub struct VM<'a> {
     /// Value stack holding values during execution.
-    pub stack: Vec<i64>,
+    ///
+    /// Memory is provided by the caller; its length is the maximal stack size.
+    pub stack: &'a mut [i64],
+    /// Memory for `LOAD8`, `STORE8` and friends, byte addressable.
+    ///
+    /// Memory is provided by the caller and must be at least as large as the program
+    /// requires. A program can only access the part it declared.
+    pub memory: &'a mut [u8],
+    /// Stack pointer.
+    ///
+    /// Number of values currently on the stack; the next push goes to this index.
+    pub sp: usize,
     /// Program counter (PC),
     ///
     /// Points to instruction in bytecode that is to be executed next.
     pub pc: usize,
+    /// Position of the instruction executed last.
+    ///
+    /// After a runtime error, this points to the start of the instruction that failed.
+    pub op_pos: usize,
     /// Frame base register
     ///
     /// Pointer to the bottom of the stack for the current frame.
@@ -47,30 +226,145 @@ pub struct VM {
     /// Let's us know how "long" the execution took.
     pub op_cnt: usize,
     /// Activate verbose activity logging during execution?
+    ///
+    /// Trace events are reported to the `observer`.
     pub trace: bool,
     /// Maximal length the stack aver was, during execution.
     pub watermark: usize,
     /// Maximal number of instructions that are allowed for execution (0 for unlimited).
     pub instruction_limit: usize,
+    /// Behaviour of `ADD`, `SUB`, `MUL` and `DIV` on overflow.
+    pub overflow: Overflow,
+    /// Execution status of the program currently loaded.
+    pub status: Status,
+    /// Native functions the host provides for `SYSCALL`.
+    pub syscalls: SyscallTable<'a>,
+    /// Receives values from `OUT` and trace events; they are discarded if there is none.
+    pub observer: Option<&'a mut dyn Observer>,
+    /// Positions in bytecode execution stops at.
+    breakpoints: [Option<usize>; MAX_BREAKPOINTS],
+    /// Variables execution stops after writing.
+    watchpoints: [Option<Watch>; MAX_WATCHPOINTS],
+    /// Watchpoint hit by the instruction that is executing.
+    watch_hit: Option<Watch>,
 }
 
-impl VM {
-    pub fn new(stack_size: usize) -> VM{
+impl<'a> VM<'a> {
+    /// Creates a VM that uses the given memory for its value stack.
+    pub fn new(stack: &'a mut [i64]) -> VM<'a> {
         VM{
-            stack: Vec::with_capacity(stack_size),
+            stack,
+            memory: &mut [],
+            sp: 0,
             pc: 0,
+            op_pos: 0,
             fb: 0,
             op_cnt: 0,
             trace: false,
             watermark: 0,
             instruction_limit: 0,
+            overflow: Overflow::default(),
+            status: Status::Finished,
+            syscalls: SyscallTable::default(),
+            observer: None,
+            breakpoints: [None; MAX_BREAKPOINTS],
+            watchpoints: [None; MAX_WATCHPOINTS],
+            watch_hit: None,
+        }
+    }
+
+    /// Sets a breakpoint at a position in bytecode.
+    ///
+    /// Execution stops before the instruction at that position is executed. Breakpoints
+    /// stay set when the VM is reset.
+    pub fn add_breakpoint(&mut self, pos: usize) -> Result<(), BreakpointError> {
+        if self.breakpoints.contains(&Some(pos)) {
+            return Ok(());
+        }
+        let slot = self.breakpoints.iter_mut().find(|b| b.is_none()).ok_or(BreakpointError::TooMany)?;
+        *slot = Some(pos);
+        Ok(())
+    }
+
+    /// Sets a breakpoint at the first instruction of a source line.
+    ///
+    /// Needs the program's debug information. Returns the position of the breakpoint.
+    #[cfg(feature = "std")]
+    pub fn add_line_breakpoint(&mut self, pgm: &Pgm, line: usize) -> Result<usize, BreakpointError> {
+        let debug = pgm.debug.as_ref().ok_or(BreakpointError::NoDebugInfo)?;
+        let pos = debug.position(line).ok_or(BreakpointError::NoCodeAtLine(line))?;
+        self.add_breakpoint(pos)?;
+        Ok(pos)
+    }
+
+    /// Removes a breakpoint; returns `false` if there was none at that position.
+    pub fn remove_breakpoint(&mut self, pos: usize) -> bool {
+        match self.breakpoints.iter_mut().find(|b| **b == Some(pos)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns the positions of all breakpoints.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().flatten().copied()
+    }
+
+    /// Watches a variable, so that execution stops after it is written.
+    pub fn add_watchpoint(&mut self, watch: Watch) -> Result<(), BreakpointError> {
+        if self.watchpoints.contains(&Some(watch)) {
+            return Ok(());
+        }
+        let slot = self.watchpoints.iter_mut().find(|w| w.is_none()).ok_or(BreakpointError::TooMany)?;
+        *slot = Some(watch);
+        Ok(())
+    }
+
+    /// Removes a watchpoint; returns `false` if the variable was not watched.
+    pub fn remove_watchpoint(&mut self, watch: Watch) -> bool {
+        match self.watchpoints.iter_mut().find(|w| **w == Some(watch)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns all watched variables.
+    pub fn watchpoints(&self) -> impl Iterator<Item = Watch> + '_ {
+        self.watchpoints.iter().flatten().copied()
+    }
+
+    /// Notes that a variable was written, so that execution stops if it is watched.
+    fn written(&mut self, var: Watch) {
+        if self.watchpoints.contains(&Some(var)) {
+            self.watch_hit = Some(var);
+        }
+    }
+
+    /// Returns the values currently on the stack, bottom first.
+    pub fn stack_values(&self) -> &[i64] {
+        &self.stack[..self.sp]
+    }
+
+    /// Reports a trace event to the observer, if tracing is active.
+    fn trace_event(&mut self, event: TraceEvent) {
+        if self.trace {
+            if let Some(observer) = self.observer.as_mut() {
+                observer.trace(&event);
+            }
         }
     }
 
     /// Tries and pops a value from value stack, respecting frame base.
     fn pop(&mut self) -> Result<i64, RuntimeError> {
-        if self.stack.len() > self.fb {
-            Ok(self.stack.pop().unwrap())
+        if self.sp > self.fb {
+            self.sp -= 1;
+            Ok(self.stack[self.sp])
         } else {
             Err(RuntimeError::StackUnderflow)
         }
@@ -78,15 +372,27 @@ impl VM {
 
     /// Tries and pushes a value to value stack, respecting stack size.
     fn push(&mut self, v: i64) -> Result<(), RuntimeError> {
-        if self.stack.len() < self.stack.capacity() {
-            self.stack.push(v);
-            self.watermark = max(self.watermark, self.stack.len());
+        if self.sp < self.stack.len() {
+            self.stack[self.sp] = v;
+            self.sp += 1;
+            self.watermark = max(self.watermark, self.sp);
             Ok(())
         } else {
             Err(RuntimeError::StackOverflow)
         }
     }
 
+    /// Picks the result of an arithmetic operation, depending on the overflow behaviour.
+    ///
+    /// `checked` is `None` if the operation overflowed.
+    fn arithmetic(&self, checked: Option<i64>, wrapped: i64, saturated: i64) -> Result<i64, RuntimeError> {
+        match self.overflow {
+            Overflow::Wrapping => Ok(wrapped),
+            Overflow::Saturating => Ok(saturated),
+            Overflow::Trapping => checked.ok_or(RuntimeError::ArithmeticOverflow),
+        }
+    }
+
     /// Reads the next byte from the bytecode, increase programm counter, and return byte.
     fn fetch_u8(&mut self, pgm: &Pgm) -> Result<u8, RuntimeError> {
         if let Some(v) = pgm.text.get(self.pc) {
@@ -114,13 +420,18 @@ impl VM {
         Ok(hi << 8 | lo)
     }
 
+    /// Reads the next four bytes from the bytecode, increase program counter by four, and return as i32.
+    fn fetch_i32(&mut self, pgm: &Pgm) -> Result<i32, RuntimeError> {
+        let hi = self.fetch_i16(pgm)? as i32;
+        let lo = self.fetch_i16(pgm)? as u16 as i32;
+        Ok(hi << 16 | lo)
+    }
+
     /// Executes a checked relative jump; Runtime error, if jump leaves program.
     fn relative_jump(&mut self, pgm: &Pgm, delta: i16) -> Result<(), RuntimeError> {
-        if self.trace {
-            println!("  Jump from {} by {}", self.pc, delta);
-        }
+        self.trace_event(TraceEvent::Jump { from: self.pc, delta });
         if delta < 0 {
-            let d = -delta as usize;
+            let d = delta.unsigned_abs() as usize;
             if self.pc >= d {
                 self.pc -= d;
                 Ok(())
@@ -138,46 +449,302 @@ impl VM {
         }
     }
 
-    /// Executes a program (encoded in bytecode).
-    pub fn run(&mut self, pgm: &Pgm) -> Result<(), RuntimeError> {
-        // initialise the VM to be in a clean start state:
-        self.stack.clear();
+    /// Returns the function calls that are active, innermost first.
+    ///
+    /// The calls are reconstructed from the frames `CALL` puts on the stack. Each frame
+    /// holds `n`, the return address, and the caller's frame base, directly below the
+    /// current frame base.
+    pub fn frames(&self, pgm: &Pgm) -> Frames<'_> {
+        // the lowest possible frame sits directly on top of the global variables:
+        Frames { stack: &self.stack[..self.sp], min: pgm.vars as usize + 3, fb: self.fb }
+    }
+
+    /// Creates the report for a runtime error, from the VM's current state.
+    fn report(&self, pgm: &Pgm, error: RuntimeError) -> RuntimeErrorReport {
+        let mut report = RuntimeErrorReport {
+            error,
+            pos: self.op_pos,
+            opcode: pgm.text.get(self.op_pos).copied(),
+            op_cnt: self.op_cnt,
+            depth: self.sp,
+            backtrace: [0; MAX_BACKTRACE],
+            backtrace_len: 0,
+        };
+        for frame in self.frames(pgm).take(MAX_BACKTRACE) {
+            report.backtrace[report.backtrace_len as usize] = frame.ret as u32;
+            report.backtrace_len += 1;
+        }
+        report
+    }
+
+    /// Checks a memory access and returns the range of bytes it covers.
+    ///
+    /// Programs can only access the memory they declared; anything else is a `MemoryFault`.
+    fn memory_range(&self, pgm: &Pgm, addr: i64, size: usize) -> Result<core::ops::Range<usize>, RuntimeError> {
+        let start = usize::try_from(addr).or(Err(RuntimeError::MemoryFault))?;
+        match start.checked_add(size) {
+            Some(end) if end <= pgm.memory as usize => Ok(start..end),
+            _ => Err(RuntimeError::MemoryFault),
+        }
+    }
+
+    /// Reads `N` bytes from memory, zero extended to an i64 (little endian).
+    fn load_memory<const N: usize>(&mut self, pgm: &Pgm) -> Result<(), RuntimeError> {
+        let addr = self.pop()?;
+        let range = self.memory_range(pgm, addr, N)?;
+        let mut bytes = [0; 8];
+        bytes[..N].copy_from_slice(&self.memory[range]);
+        self.push(u64::from_le_bytes(bytes) as i64)
+    }
+
+    /// Writes the lowest `N` bytes of a value to memory (little endian).
+    fn store_memory<const N: usize>(&mut self, pgm: &Pgm) -> Result<(), RuntimeError> {
+        let v = self.pop()?;
+        let addr = self.pop()?;
+        let range = self.memory_range(pgm, addr, N)?;
+        self.memory[range].copy_from_slice(&v.to_le_bytes()[..N]);
+        Ok(())
+    }
+
+    /// Calls a native function from the syscall table, after checking its stack effect.
+    fn syscall(&mut self, id: u8) -> Result<(), RuntimeError> {
+        let syscall = self.syscalls.get_mut(id).ok_or(RuntimeError::UnknownSyscall(id))?;
+        let pops = syscall.pops();
+        let pushes = syscall.pushes();
+        if pops > MAX_SYSCALL_VALUES || pushes > MAX_SYSCALL_VALUES {
+            return Err(RuntimeError::InvalidSyscall(id));
+        }
+        if self.sp < self.fb + pops {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        let start = self.sp - pops;
+        if start + pushes > self.stack.len() {
+            return Err(RuntimeError::StackOverflow);
+        }
+        // arguments are passed directly from the stack, results are collected first:
+        let mut results = [0; MAX_SYSCALL_VALUES];
+        syscall.call(&self.stack[start..self.sp], &mut results[..pushes])?;
+        self.sp = start;
+        for v in &results[..pushes] {
+            self.push(*v)?;
+        }
+        Ok(())
+    }
+
+    /// Puts the VM in a clean start state for executing a program.
+    ///
+    /// Clears the stack and the program's memory, creates the program's global variables and
+    /// points the program counter to the program's start. Must be called before the first
+    /// `step` of a program. Fails with `MemoryFault`, if `memory` is smaller than the program
+    /// needs.
+    pub fn reset(&mut self, pgm: &Pgm) -> Result<(), RuntimeErrorReport> {
+        self.sp = 0;
         self.pc = 0;
+        self.op_pos = 0;
+        self.fb = 0;
         self.op_cnt = 0;
         self.watermark = 0;
+        self.status = Status::Running;
+        if let Some(memory) = self.memory.get_mut(..pgm.memory as usize) {
+            memory.fill(0);
+        } else {
+            let report = self.report(pgm, RuntimeError::MemoryFault);
+            self.status = Status::Faulted(report.clone());
+            return Err(report);
+        }
         // create global variables in stack:
         for _ in 0..pgm.vars {
-            self.push(0)?;
+            if let Err(e) = self.push(0) {
+                let report = self.report(pgm, e);
+                self.status = Status::Faulted(report.clone());
+                return Err(report);
+            }
         }
         self.fb = pgm.vars as usize;
+        Ok(())
+    }
 
-        // Loop going through the whole program, one instruction at a time.
-        loop {
-            // Log the vm's complete state, so we can follow what happens in console:
-            if self.trace {
-                println!("{:?}", self);
+    /// Captures the execution state of the program that is running.
+    ///
+    /// The snapshot borrows stack and memory from the VM. Only running programs can be
+    /// captured; a program that finished or faulted has nothing to continue.
+    pub fn snapshot(&self, pgm: &Pgm) -> Result<Snapshot<'_>, SnapshotError> {
+        if !matches!(self.status, Status::Running | Status::Stopped(_)) {
+            return Err(SnapshotError::NotRunning);
+        }
+        Ok(Snapshot::new(pgm.identity(), self.pc, self.fb, self.op_cnt, self.watermark,
+                         &self.stack[..self.sp], &self.memory[..pgm.memory as usize]))
+    }
+
+    /// Continues a program from a snapshot, exactly where it was taken.
+    ///
+    /// The snapshot must have been taken from the same program, and must fit into this VM's
+    /// stack and memory. If it is rejected, the VM is left unchanged.
+    ///
+    /// If there is a breakpoint where the snapshot continues, the program is stopped at it,
+    /// so that it continues past the breakpoint, instead of stopping at it a second time.
+    pub fn restore(&mut self, pgm: &Pgm, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        if snapshot.program != pgm.identity() {
+            return Err(SnapshotError::ProgramMismatch);
+        }
+        let depth = snapshot.depth();
+        if depth > self.stack.len() {
+            return Err(SnapshotError::StackTooSmall);
+        }
+        if self.memory.len() < pgm.memory as usize {
+            return Err(SnapshotError::MemoryTooSmall);
+        }
+        // the state must be one that execution could have reached:
+        let vars = pgm.vars as usize;
+        if snapshot.memory.len() != pgm.memory as usize
+            || snapshot.pc >= pgm.text.len()
+            || !pgm.is_instruction_start(snapshot.pc)
+            || depth < vars
+            || snapshot.fb < vars
+            || snapshot.fb > depth
+            || snapshot.watermark < depth
+            || snapshot.watermark > self.stack.len() {
+            return Err(SnapshotError::InvalidState);
+        }
+        // every frame must be one `CALL` could have created, down to the global variables:
+        let mut fb = snapshot.fb;
+        while fb > vars {
+            let invalid = SnapshotError::InvalidFrame(fb);
+            if fb < vars + 3 {
+                return Err(invalid);
             }
-            // Fetch next opcode from program (increases program counter):
-            let opcode = self.fetch_u8(&pgm)?;
-            // Limit execution by number of instructions that will be executed:
-            if self.instruction_limit != 0 && self.op_cnt >= self.instruction_limit {
-                return Err(RuntimeError::InstructionLimitExceeded);
+            let value = |ix: usize| snapshot.value(ix).ok_or(SnapshotError::InvalidFrame(fb));
+            let n = usize::try_from(value(fb - 3)?).map_err(|_| invalid.clone())?;
+            let ret = usize::try_from(value(fb - 2)?).map_err(|_| invalid.clone())?;
+            let caller = usize::try_from(value(fb - 1)?).map_err(|_| invalid.clone())?;
+            if n > depth - fb || !pgm.is_instruction_start(ret) || caller < vars || caller > fb - 3 {
+                return Err(invalid);
             }
-            // We count the number of instructions we execute:
-            self.op_cnt += 1;
-            // If we are done, break loop and stop execution:
-            if opcode == op::FIN {
+            fb = caller;
+        }
+        for (slot, v) in self.stack.iter_mut().zip(snapshot.stack()) {
+            *slot = v;
+        }
+        self.memory[..snapshot.memory.len()].copy_from_slice(snapshot.memory);
+        self.sp = depth;
+        self.pc = snapshot.pc;
+        self.op_pos = snapshot.pc;
+        self.fb = snapshot.fb;
+        self.op_cnt = snapshot.op_cnt;
+        self.watermark = snapshot.watermark;
+        self.status = if self.breakpoints.contains(&Some(snapshot.pc)) {
+            Status::Stopped(Stop::Breakpoint(snapshot.pc))
+        } else {
+            Status::Running
+        };
+        Ok(())
+    }
+
+    /// Executes a single instruction of a program.
+    ///
+    /// The VM's state (`pc`, `fb`, `stack`, ...) is kept between calls, so execution can
+    /// be paused and continued at any time. Once the program has finished or faulted, the
+    /// VM stays in that status until `reset` is called.
+    ///
+    /// If the next instruction is a breakpoint, it is not executed, but `Status::Stopped`
+    /// is returned; the following call executes it. If the instruction writes a watched
+    /// variable, `Status::Stopped` is returned after executing it.
+    pub fn step(&mut self, pgm: &Pgm) -> Status {
+        match self.status {
+            Status::Finished | Status::Faulted(_) => return self.status.clone(),
+            // continue after the stop, executing the instruction at the breakpoint:
+            Status::Stopped(Stop::Breakpoint(pos)) if pos == self.pc => {},
+            _ => {
+                if self.breakpoints.contains(&Some(self.pc)) {
+                    self.status = Status::Stopped(Stop::Breakpoint(self.pc));
+                    return self.status.clone();
+                }
+            },
+        }
+        self.watch_hit = None;
+        self.status = match self.execute_next(pgm) {
+            Ok(true) => match self.watch_hit {
+                Some(watch) => Status::Stopped(Stop::Watchpoint(watch)),
+                None => Status::Running,
+            },
+            Ok(false) => Status::Finished,
+            Err(e) => Status::Faulted(self.report(pgm, e)),
+        };
+        // Execution terminated. Report the final state of the VM:
+        if self.trace && self.status == Status::Finished {
+            if let Some(observer) = self.observer.as_mut() {
+                observer.trace(&TraceEvent::Terminated { op_cnt: self.op_cnt, stack: &self.stack[..self.sp] });
+            }
+        }
+        self.status.clone()
+    }
+
+    /// Executes up to `n` instructions of a program.
+    ///
+    /// Returns early, if the program finishes, faults, or stops at a breakpoint or watchpoint
+    /// before. Returns `Status::Running` if there is more to execute.
+    pub fn run_for(&mut self, pgm: &Pgm, n: usize) -> Status {
+        for _ in 0..n {
+            if self.step(pgm) != Status::Running {
                 break;
             }
-            // Execute the current instruction (with the opcode we loaded already):
-            self.execute_op(&pgm, opcode)?;
         }
-        // Execution terminated. Output the final state of the VM:
+        self.status.clone()
+    }
+
+    /// Executes a program (encoded in bytecode).
+    ///
+    /// Resets the VM and runs the program until it terminates, fails, or stops at a
+    /// breakpoint or watchpoint. Returns `Status::Finished` or `Status::Stopped`; after a
+    /// stop, execution continues with `resume`.
+    pub fn run(&mut self, pgm: &Pgm) -> Result<Status, RuntimeErrorReport> {
+        self.reset(pgm)?;
+        self.resume(pgm)
+    }
+
+    /// Continues executing a program, after a stop or a `step`.
+    ///
+    /// Runs until the program terminates, fails, or stops again, like `run`.
+    pub fn resume(&mut self, pgm: &Pgm) -> Result<Status, RuntimeErrorReport> {
+        // Loop going through the whole program, one instruction at a time.
+        loop {
+            match self.step(pgm) {
+                Status::Running => {},
+                Status::Faulted(e) => return Err(e),
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Fetches and executes the next instruction.
+    ///
+    /// Returns `false` if the program terminated with `FIN`.
+    fn execute_next(&mut self, pgm: &Pgm) -> Result<bool, RuntimeError> {
+        // Fetch next opcode from program (increases program counter):
+        let pc = self.pc;
+        self.op_pos = pc;
+        let opcode = self.fetch_u8(pgm)?;
+        // Limit execution by number of instructions that will be executed:
+        if self.instruction_limit != 0 && self.op_cnt >= self.instruction_limit {
+            return Err(RuntimeError::InstructionLimitExceeded);
+        }
+        // We count the number of instructions we execute:
+        self.op_cnt += 1;
+        // Log the vm's complete state, so we can follow what happens:
         if self.trace {
-            println!("Terminated!");
-            println!("{:?}", self);
+            if let Some(observer) = self.observer.as_mut() {
+                observer.trace(&TraceEvent::Instruction {
+                    pc, opcode, fb: self.fb, op_cnt: self.op_cnt, stack: &self.stack[..self.sp],
+                });
+            }
         }
-        Ok(())
+        // If we are done, stop execution:
+        if opcode == op::FIN {
+            return Ok(false);
+        }
+        // Execute the current instruction (with the opcode we loaded already):
+        self.execute_op(pgm, opcode)?;
+        Ok(true)
     }
 
     /// Executes an instruction, using the opcode passed.
@@ -185,9 +752,6 @@ impl VM {
     /// This might load more data from the program (opargs) and
     /// manipulate the stack (push, pop).
     fn execute_op(&mut self, pgm: &Pgm, opcode: u8) -> Result<(), RuntimeError> {
-        if self.trace {
-            println!("Executing op 0x{:02x}", opcode);
-        }
         match opcode {
             op::NOP => {
                 // do nothing
@@ -205,27 +769,53 @@ impl VM {
             },
             op::OUT => {
                 let v = self.pop()?;
-                println!("Out: {} (@{})", v, self.op_cnt);
+                if let Some(observer) = self.observer.as_mut() {
+                    observer.out(v, self.op_cnt);
+                }
                 Ok(())
             },
+            op::SYSCALL => {
+                let id = self.fetch_u8(pgm)?;
+                self.syscall(id)
+            },
             op::PUSH_U8 => {
                 let v = self.fetch_u8(pgm)?;
                 self.push(v as i64)
             },
+            op::PUSH_I8 => {
+                let v = self.fetch_i8(pgm)?;
+                self.push(v as i64)
+            },
+            op::PUSH_I16 => {
+                let v = self.fetch_i16(pgm)?;
+                self.push(v as i64)
+            },
+            op::PUSH_I32 => {
+                let v = self.fetch_i32(pgm)?;
+                self.push(v as i64)
+            },
+            op::PUSH_C => {
+                let idx = self.fetch_u8(pgm)?;
+                let v = pgm.constant(idx).ok_or(RuntimeError::InvalidConstant(idx))?;
+                self.push(v)
+            },
             op::ADD => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a + b)
+                let v = self.arithmetic(a.checked_add(b), a.wrapping_add(b), a.saturating_add(b))?;
+                self.push(v)
             },
             op::SUB => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a - b)
+                let v = self.arithmetic(a.checked_sub(b), a.wrapping_sub(b), a.saturating_sub(b))?;
+                self.push(v)
             },
             op::MUL => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a * b)
+                let v = self.arithmetic(a.checked_mul(b), a.wrapping_mul(b), a.saturating_mul(b))?;
+                self.push(v)
             },
             op::DIV => {
                 let b = self.pop()?;
@@ -233,7 +823,9 @@ impl VM {
                 if b == 0 {
                     Err(RuntimeError::DivisionByZero)
                 } else {
-                    self.push(a / b)
+                    // only `i64::MIN / -1` can overflow:
+                    let v = self.arithmetic(a.checked_div(b), a.wrapping_div(b), a.saturating_div(b))?;
+                    self.push(v)
                 }
             },
             op::MOD => {
@@ -242,7 +834,8 @@ impl VM {
                 if b == 0 {
                     Err(RuntimeError::DivisionByZero)
                 } else {
-                    self.push(a % b)
+                    // the remainder always fits; `i64::MIN % -1` is 0:
+                    self.push(a.wrapping_rem(b))
                 }
             },
             op::ROT => {
@@ -251,6 +844,55 @@ impl VM {
                 self.push(a)?;
                 self.push(b)
             }
+            op::AND => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a & b)
+            },
+            op::OR => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a | b)
+            },
+            op::XOR => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a ^ b)
+            },
+            op::NOT => {
+                let a = self.pop()?;
+                self.push(!a)
+            },
+            op::SHL => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                // shifting by 64 or more (or by a negative amount) moves every bit out:
+                self.push(if (0..64).contains(&b) { a << b } else { 0 })
+            },
+            op::SHR => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if (0..64).contains(&b) { ((a as u64) >> b) as i64 } else { 0 })
+            },
+            op::SAR => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if (0..64).contains(&b) { a >> b } else { a >> 63 })
+            },
+            op::LOAD_RO => {
+                let offset = self.fetch_i16(pgm)? as u16;
+                let entry = pgm.rodata_entry(offset as usize).ok_or(RuntimeError::InvalidRoData)?;
+                let index = self.pop()?;
+                let index = usize::try_from(index).or(Err(RuntimeError::MemoryFault))?;
+                let v = entry.get(index).ok_or(RuntimeError::MemoryFault)?;
+                self.push(v)
+            },
+            op::LOAD8 => self.load_memory::<1>(pgm),
+            op::STORE8 => self.store_memory::<1>(pgm),
+            op::LOAD16 => self.load_memory::<2>(pgm),
+            op::STORE16 => self.store_memory::<2>(pgm),
+            op::LOAD32 => self.load_memory::<4>(pgm),
+            op::STORE32 => self.store_memory::<4>(pgm),
             op::GOTO => {
                 let d = self.fetch_i16(pgm)?;
                 self.relative_jump(pgm, d)
@@ -316,6 +958,7 @@ impl VM {
                 } else {
                     let v = self.pop()?;
                     self.stack[idx as usize] = v;
+                    self.written(Watch::Global(idx));
                     Ok(())
                 }
             },
@@ -330,17 +973,18 @@ impl VM {
             },
             op::STORE_L => {
                 let idx = self.fetch_u8(pgm)? as usize;
-                if self.fb + idx >= self.stack.len() {
+                if self.fb + idx >= self.sp {
                     Err(RuntimeError::StackOverflow)
                 } else {
                     let v = self.pop()?;
                     self.stack[self.fb + idx] = v;
+                    self.written(Watch::Local(idx as u8));
                     Ok(())
                 }
             },
             op::LOAD_L => {
                 let idx = self.fetch_u8(pgm)? as usize;
-                if self.fb + idx >= self.stack.len() {
+                if self.fb + idx >= self.sp {
                     Err(RuntimeError::StackOverflow)
                 } else {
                     self.push(self.stack[self.fb + idx])?;
@@ -349,53 +993,63 @@ impl VM {
             },
             op::SWAP_L => {
                 let idx = self.fetch_u8(pgm)? as usize;
-                if self.fb + idx >= self.stack.len() {
+                if self.fb + idx >= self.sp {
                     Err(RuntimeError::StackOverflow)
                 } else {
                     let v = self.pop()?;
                     self.push(self.stack[self.fb + idx])?;
                     self.stack[self.fb + idx] = v;
+                    self.written(Watch::Local(idx as u8));
                     Ok(())
                 }
             },
             op::CALL => {
                 let d = self.fetch_i16(pgm)?;
-                let n = self.pop()? as usize;
-                if self.stack.len() < self.fb + n {
+                let n = self.pop()?;
+                let n = match usize::try_from(n) {
+                    Ok(n) if n <= self.sp - self.fb => n,
                     // there are not enough values on the stack to pass to the function called
-                    return Err(RuntimeError::StackUnderflow);
-                }
+                    _ => return Err(RuntimeError::StackUnderflow),
+                };
                 // push frame to stack
                 self.push(n as i64)?;
                 self.push(self.pc as i64)?;
                 self.push(self.fb as i64)?;
                 // move function parameters to the top, move the frame date down:
-                let end = self.stack.len();
+                let end = self.sp;
                 let fstart = end - 3;
-                self.stack.moveslice(fstart..end, fstart-n);
+                self.stack[fstart - n..end].rotate_right(3);
                 // move frame base, so that frame starts at first parameter:
-                self.fb = self.stack.len() - n;
+                self.fb = self.sp - n;
                 // jump into function:
-                self.relative_jump(pgm, d)
+                let ret = self.pc;
+                self.relative_jump(pgm, d)?;
+                self.trace_event(TraceEvent::Call { ret, dest: self.pc, args: n });
+                Ok(())
             },
             op::RET => {
-                let n = *self.stack.get(self.fb - 3).unwrap() as usize;
-                if self.stack.len() != self.fb + n {
+                if self.fb < pgm.vars as usize + 3 {
+                    // there is no frame, we are not inside a function
                     return Err(RuntimeError::InvalidReturn);
                 }
-                // read and remove frame data:
                 let upper = self.fb - 3;
-                let n = self.stack.remove(upper) as usize;
-                self.pc = self.stack.remove(upper) as usize;
-                self.fb = self.stack.remove(upper) as usize;
-                let should = upper + n;
-                // remove excessive values on top of stack:
-                while self.stack.len() > should {
-                    self.pop()?;
+                // the frame might come from a restored snapshot, do not trust it:
+                let n = usize::try_from(self.stack[upper]).or(Err(RuntimeError::InvalidReturn))?;
+                if self.fb.checked_add(n) != Some(self.sp) {
+                    return Err(RuntimeError::InvalidReturn);
                 }
-                while self.stack.len() < should {
-                    self.push(0)?;
+                let pc = usize::try_from(self.stack[upper + 1]).or(Err(RuntimeError::InvalidReturn))?;
+                let fb = usize::try_from(self.stack[upper + 2]).or(Err(RuntimeError::InvalidReturn))?;
+                if fb < pgm.vars as usize || fb > upper {
+                    return Err(RuntimeError::InvalidReturn);
                 }
+                // read frame data:
+                self.pc = pc;
+                self.fb = fb;
+                // remove frame data, by moving the n values of the function down:
+                self.stack.copy_within(upper + 3..self.sp, upper);
+                self.sp -= 3;
+                self.trace_event(TraceEvent::Return { to: self.pc });
                 Ok(())
             },
             _ => {
@@ -404,3 +1058,340 @@ impl VM {
         }
     }
 }
+
+#[cfg(all(test, feature = "asm"))]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::fixture::{self, program};
+    use crate::pgm::crc32;
+
+    /// Squares a number in a function, so that there is a frame on the stack.
+    const SQUARE: &str = "
+        push_u8 7
+        push_u8 1
+        call square
+        pop
+        fin
+    square:
+        local x
+        load_l x
+        load_l x
+        mul
+        store_l x
+        ret
+    ";
+
+    /// Assembles and runs a program; returns what it output, and how it ended.
+    fn outputs(source: &str, overflow: Overflow) -> (Vec<i64>, Result<Status, RuntimeError>) {
+        let pgm = asm::assemble("test", source, &[]).unwrap();
+        let ran = fixture::run(&pgm, 16, |vm| vm.overflow = overflow);
+        (ran.out, ran.result)
+    }
+
+    /// Offset of the stack values in an encoded snapshot.
+    const STACK_START: usize = 4 + 1 + 4 * 6 + 8;
+
+    /// Runs `SQUARE` until it is inside the function, and encodes a snapshot of that.
+    fn snapshot_in_function(pgm: &Pgm) -> Vec<u8> {
+        let mut stack = [0; 16];
+        let mut vm = VM::new(&mut stack);
+        let pos = pgm.debug.as_ref().unwrap().label("square").unwrap();
+        vm.add_breakpoint(pos).unwrap();
+        assert_eq!(vm.run(pgm), Ok(Status::Stopped(Stop::Breakpoint(pos))));
+        vm.snapshot(pgm).unwrap().to_bytes()
+    }
+
+    /// Replaces a value on the stack of an encoded snapshot, with a correct checksum.
+    fn tamper(data: &mut [u8], ix: usize, value: i64) {
+        let start = STACK_START + ix * 8;
+        data[start..start + 8].copy_from_slice(&value.to_be_bytes());
+        let len = data.len();
+        let crc = crc32(&data[..len - 4]);
+        data[len - 4..].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    #[test]
+    fn restore_continues_in_function() {
+        let pgm = asm::assemble("square", SQUARE, &[]).unwrap();
+        let data = snapshot_in_function(&pgm);
+        let snapshot = Snapshot::from_bytes(&data).unwrap();
+        let mut stack = [0; 16];
+        let mut vm = VM::new(&mut stack);
+        vm.restore(&pgm, &snapshot).unwrap();
+        assert_eq!(vm.resume(&pgm), Ok(Status::Finished));
+    }
+
+    #[test]
+    fn restore_at_breakpoint_continues_past_it() {
+        let pgm = asm::assemble("square", SQUARE, &[]).unwrap();
+        let data = snapshot_in_function(&pgm);
+        let snapshot = Snapshot::from_bytes(&data).unwrap();
+        let mut stack = [0; 16];
+        let mut vm = VM::new(&mut stack);
+        let pos = pgm.debug.as_ref().unwrap().label("square").unwrap();
+        vm.add_breakpoint(pos).unwrap();
+        vm.restore(&pgm, &snapshot).unwrap();
+        assert_eq!(vm.status, Status::Stopped(Stop::Breakpoint(pos)));
+        assert_eq!(vm.resume(&pgm), Ok(Status::Finished));
+    }
+
+    #[test]
+    fn restore_rejects_tampered_frames() {
+        let pgm = asm::assemble("square", SQUARE, &[]).unwrap();
+        // the frame is `n`, return address, caller's frame base, then the parameter:
+        for (ix, value) in [(0, -1), (0, 2), (1, -1), (1, 5), (1, 1000), (2, 1), (2, -1)] {
+            let mut data = snapshot_in_function(&pgm);
+            tamper(&mut data, ix, value);
+            let snapshot = Snapshot::from_bytes(&data).unwrap();
+            let mut stack = [0; 16];
+            let mut vm = VM::new(&mut stack);
+            assert_eq!(vm.restore(&pgm, &snapshot), Err(SnapshotError::InvalidFrame(3)), "cell {} = {}", ix, value);
+        }
+    }
+
+    #[test]
+    fn ret_with_corrupted_frame_fails() {
+        let pgm = asm::assemble("square", SQUARE, &[]).unwrap();
+        for (ix, value) in [(0, -1), (0, i64::MAX), (1, -1), (2, -1), (2, 10)] {
+            let mut stack = [0; 16];
+            let mut vm = VM::new(&mut stack);
+            let pos = pgm.debug.as_ref().unwrap().label("square").unwrap();
+            vm.add_breakpoint(pos).unwrap();
+            vm.run(&pgm).unwrap();
+            vm.stack[ix] = value;
+            let report = vm.resume(&pgm).unwrap_err();
+            assert_eq!(report.error, RuntimeError::InvalidReturn, "cell {} = {}", ix, value);
+        }
+    }
+
+    #[test]
+    fn jump_before_start_fails() {
+        // the furthest jump backwards there is:
+        let pgm = program(&[op::GOTO, 0x80, 0x00, op::FIN]);
+        assert_eq!(fixture::run(&pgm, 16, |_| {}).result, Err(RuntimeError::InvalidJump));
+    }
+
+    #[test]
+    fn overflow_modes() {
+        let cases = [
+            ("add", i64::MAX, 1, i64::MIN, i64::MAX),
+            ("sub", i64::MIN, 1, i64::MAX, i64::MIN),
+            ("mul", i64::MAX, 2, -2, i64::MAX),
+            ("mul", i64::MIN, -1, i64::MIN, i64::MAX),
+            ("div", i64::MIN, -1, i64::MIN, i64::MAX),
+        ];
+        for (op, a, b, wrapped, saturated) in cases {
+            let source = format!("push {}\npush {}\n{}\nout\nfin", a, b, op);
+            assert_eq!(outputs(&source, Overflow::Wrapping), (vec![wrapped], Ok(Status::Finished)), "{}", op);
+            assert_eq!(outputs(&source, Overflow::Saturating), (vec![saturated], Ok(Status::Finished)), "{}", op);
+            assert_eq!(outputs(&source, Overflow::Trapping), (vec![], Err(RuntimeError::ArithmeticOverflow)), "{}", op);
+        }
+    }
+
+    #[test]
+    fn division_in_every_overflow_mode() {
+        for overflow in [Overflow::Wrapping, Overflow::Saturating, Overflow::Trapping] {
+            // the remainder of `i64::MIN / -1` fits, it is 0:
+            let source = format!("push {}\npush -1\nmod\nout\npush -7\npush 2\nmod\nout\npush -7\npush 2\ndiv\nout\nfin", i64::MIN);
+            assert_eq!(outputs(&source, overflow), (vec![0, -1, -3], Ok(Status::Finished)), "{:?}", overflow);
+            for op in ["div", "mod"] {
+                let source = format!("push_u8 1\npush_u8 0\n{}\nfin", op);
+                assert_eq!(outputs(&source, overflow), (vec![], Err(RuntimeError::DivisionByZero)), "{:?}", overflow);
+            }
+        }
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        let source = "push_u8 12\npush_u8 10\nand\nout\npush_u8 12\npush_u8 10\nor\nout\n\
+                      push_u8 12\npush_u8 10\nxor\nout\npush_u8 0\nnot\nout\nfin";
+        assert_eq!(outputs(source, Overflow::Trapping), (vec![8, 14, 6, -1], Ok(Status::Finished)));
+    }
+
+    #[test]
+    fn shifts_by_any_amount() {
+        let cases = [
+            ("shl", 1, 63, i64::MIN),
+            ("shl", 1, 64, 0),
+            ("shl", 1, -1, 0),
+            ("shr", -1, 60, 15),
+            ("shr", -1, 64, 0),
+            ("shr", -1, -1, 0),
+            ("sar", -8, 1, -4),
+            ("sar", -8, 64, -1),
+            ("sar", -8, -1, -1),
+            ("sar", 8, 1000, 0),
+        ];
+        for (op, a, b, expected) in cases {
+            let source = format!("push {}\npush {}\n{}\nout\nfin", a, b, op);
+            assert_eq!(outputs(&source, Overflow::Trapping), (vec![expected], Ok(Status::Finished)), "{} {} {}", a, op, b);
+        }
+    }
+
+    #[test]
+    fn pushes_of_every_size() {
+        let source = "push_u8 255\nout\npush_i8 -128\nout\npush_i16 -32768\nout\n\
+                      push_i32 -2147483648\nout\npush 0x123456789\nout\nfin";
+        let expected = vec![255, -128, -32768, -2147483648, 0x123456789];
+        assert_eq!(outputs(source, Overflow::Trapping), (expected, Ok(Status::Finished)));
+    }
+
+    #[test]
+    fn push_of_missing_constant_fails() {
+        let pgm = program(&[op::PUSH_C, 0, op::OUT, op::FIN]);
+        assert_eq!(fixture::run(&pgm, 16, |_| {}).result, Err(RuntimeError::InvalidConstant(0)));
+    }
+
+    #[test]
+    fn memory_is_little_endian() {
+        let source = "
+            memory 8
+            push_u8 0
+            push 0x11223344
+            store32
+            push_u8 0
+            load8
+            out
+            push_u8 1
+            load16
+            out
+            push_u8 0
+            load32
+            out
+            push_u8 7
+            push -1
+            store8
+            push_u8 6
+            load16
+            out
+            fin
+        ";
+        let expected = vec![0x44, 0x2233, 0x11223344, 0xff00];
+        assert_eq!(outputs(source, Overflow::Trapping), (expected, Ok(Status::Finished)));
+    }
+
+    #[test]
+    fn memory_access_out_of_bounds_fails() {
+        // the last four bytes can be read, but not a single byte more:
+        let ok = "memory 8\npush_u8 4\nload32\nout\nfin";
+        assert_eq!(outputs(ok, Overflow::Trapping), (vec![0], Ok(Status::Finished)));
+        for access in ["push_u8 5\nload32", "push -1\nload8", "push_u8 8\npush_u8 1\nstore8", "push_u8 7\npush_u8 1\nstore16"] {
+            let source = format!("memory 8\n{}\nfin", access);
+            assert_eq!(outputs(&source, Overflow::Trapping), (vec![], Err(RuntimeError::MemoryFault)), "{}", access);
+        }
+    }
+
+    #[test]
+    fn missing_memory_fails() {
+        let pgm = asm::assemble("test", "memory 8\nfin", &[]).unwrap();
+        let mut stack = [0; 4];
+        let mut memory = [0; 4];
+        let mut vm = VM::new(&mut stack);
+        vm.memory = &mut memory;
+        assert_eq!(vm.run(&pgm).unwrap_err().error, RuntimeError::MemoryFault);
+    }
+
+    #[test]
+    fn rodata_tables_and_strings() {
+        let source = "
+            .data values: -5, 0x123456789
+            .bytes bytes: 200, 1
+            .string text: \"A\"
+            push_u8 0
+            load_ro values
+            out
+            push_u8 1
+            load_ro values
+            out
+            push_u8 0
+            load_ro bytes
+            out
+            push_u8 0
+            load_ro text
+            out
+            fin
+        ";
+        assert_eq!(outputs(source, Overflow::Trapping), (vec![-5, 0x123456789, 200, 65], Ok(Status::Finished)));
+    }
+
+    #[test]
+    fn rodata_access_out_of_bounds_fails() {
+        for index in ["2", "-1"] {
+            let source = format!(".bytes bytes: 1, 2\npush {}\nload_ro bytes\nfin", index);
+            assert_eq!(outputs(&source, Overflow::Trapping), (vec![], Err(RuntimeError::MemoryFault)), "{}", index);
+        }
+        // no entry starts at offset 0 of an empty segment:
+        let pgm = program(&[op::PUSH_U8, 0, op::LOAD_RO, 0, 0, op::FIN]);
+        assert_eq!(fixture::run(&pgm, 16, |_| {}).result, Err(RuntimeError::InvalidRoData));
+    }
+
+    /// Counts down a global variable, outputting every value.
+    const COUNTDOWN: &str = "
+            push_u8 3
+            store n
+        loop:
+            load n
+            out
+            load n
+            push_u8 1
+            sub
+            dup
+            store n
+            ifgt loop
+            fin
+    ";
+
+    #[test]
+    fn breakpoint_stops_every_time() {
+        let pgm = asm::assemble("countdown", COUNTDOWN, &[]).unwrap();
+        let pos = pgm.debug.as_ref().unwrap().label("loop").unwrap();
+        let mut output = fixture::Output::default();
+        let mut stack = [0; 16];
+        let mut vm = VM::new(&mut stack);
+        vm.observer = Some(&mut output);
+        vm.add_breakpoint(pos).unwrap();
+        // the instruction at the breakpoint is not executed, yet:
+        assert_eq!(vm.run(&pgm), Ok(Status::Stopped(Stop::Breakpoint(pos))));
+        assert_eq!((vm.pc, vm.op_cnt), (pos, 2));
+        // resuming executes it, and stops there again in the next iteration:
+        assert_eq!(vm.resume(&pgm), Ok(Status::Stopped(Stop::Breakpoint(pos))));
+        assert_eq!(vm.op_cnt, 2 + 8);
+        assert!(vm.remove_breakpoint(pos));
+        assert!(!vm.remove_breakpoint(pos));
+        assert_eq!(vm.breakpoints().count(), 0);
+        assert_eq!(vm.resume(&pgm), Ok(Status::Finished));
+        assert_eq!(output.0, vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn watchpoint_stops_after_write() {
+        let pgm = asm::assemble("countdown", COUNTDOWN, &[]).unwrap();
+        let mut stack = [0; 16];
+        let mut vm = VM::new(&mut stack);
+        vm.add_watchpoint(Watch::Global(0)).unwrap();
+        // locals are not written by this program:
+        vm.add_watchpoint(Watch::Local(0)).unwrap();
+        assert_eq!(vm.run(&pgm), Ok(Status::Stopped(Stop::Watchpoint(Watch::Global(0)))));
+        // the `store` has been executed:
+        assert_eq!((vm.op_pos, vm.stack_values()), (2, &[3][..]));
+        assert_eq!(vm.resume(&pgm), Ok(Status::Stopped(Stop::Watchpoint(Watch::Global(0)))));
+        assert_eq!(vm.stack_values(), &[2, 2]);
+        assert!(vm.remove_watchpoint(Watch::Global(0)));
+        assert!(!vm.remove_watchpoint(Watch::Global(0)));
+        assert_eq!(vm.watchpoints().collect::<Vec<_>>(), vec![Watch::Local(0)]);
+        assert_eq!(vm.resume(&pgm), Ok(Status::Finished));
+    }
+
+    #[test]
+    fn too_many_breakpoints() {
+        let mut stack = [0; 4];
+        let mut vm = VM::new(&mut stack);
+        for pos in 0..MAX_BREAKPOINTS {
+            vm.add_breakpoint(pos).unwrap();
+        }
+        // setting one that exists is fine:
+        assert_eq!(vm.add_breakpoint(0), Ok(()));
+        assert_eq!(vm.add_breakpoint(MAX_BREAKPOINTS), Err(BreakpointError::TooMany));
+    }
+}